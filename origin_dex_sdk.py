@@ -4,6 +4,42 @@ from dataclasses import dataclass
 import math
 from typing import Mapping, Sequence, Tuple
 
+# Mirrors of the on-chain constants in programs/origin_dex/src/lib.rs.
+PARAM_SCALE = 1_000_000
+
+FUNCTION_LINEAR = 1
+FUNCTION_LOG = 2
+FUNCTION_SPOT = 3
+FUNCTION_CURVE = 4
+FUNCTION_BIDASK = 5
+FUNCTION_EXPONENTIAL = 6
+FUNCTION_POWER = 7
+FUNCTION_WALL_DECAY = 8
+# Normalized presets: weight(d) = base + amp * f(x), x = (d - 1) / (maxD - 1).
+# params: [base, amp, shape, midpoint, unused]
+FUNCTION_PRESET_POWER = 9
+FUNCTION_PRESET_EXP = 10
+FUNCTION_PRESET_LOG = 11
+FUNCTION_PRESET_SIGMOID = 12
+
+# SDK family -> (program function type, program param names in order).
+FAMILY_FUNCTIONS = {
+    "meteora_spot": (FUNCTION_SPOT, ()),
+    "meteora_curve": (FUNCTION_CURVE, ("sigma",)),
+    "meteora_bidask": (FUNCTION_BIDASK, ("sigma", "edge_boost")),
+    "exponential": (FUNCTION_EXPONENTIAL, ("ratio",)),
+    "power": (FUNCTION_POWER, ("p", "c")),
+    "wall_decay": (FUNCTION_WALL_DECAY, ("wall_bins", "ratio")),
+}
+
+# Mirrors of programs/origin_dex/src/math.rs.
+FIXED_ONE = 10**12
+FIXED_LN_2 = 693_147_180_560
+FIXED_EXP_MAX = 60 * FIXED_ONE
+FIXED_EXP_MIN = -28 * FIXED_ONE
+_I128_MIN = -(2**127)
+_I128_MAX = 2**127 - 1
+
 
 @dataclass(frozen=True)
 class AllocationResult:
@@ -67,6 +103,244 @@ def weights_from_function(
     return tuple(weights)
 
 
+def program_function_spec(
+    family: str, bins: int, params: Mapping[str, float]
+) -> Tuple[int, Tuple[int, ...]]:
+    """Encode an SDK family as the program's (function_type, params) pair.
+
+    Defaults match `weights_from_function`; values are scaled by PARAM_SCALE.
+    """
+    if family not in FAMILY_FUNCTIONS:
+        raise ValueError(f"unknown function family: {family}")
+    weights_from_function(family, bins, params)
+    function_type, names = FAMILY_FUNCTIONS[family]
+    defaults = {
+        "sigma": bins / 2 if bins else 1,
+        "edge_boost": 1.5,
+        "c": 0.0,
+        "wall_bins": 0,
+    }
+    values = [params.get(name, defaults.get(name)) for name in names]
+    if family == "wall_decay":
+        values[0] = int(values[0])
+    encoded = [round(float(value) * PARAM_SCALE) for value in values]
+    return function_type, tuple(encoded + [0] * (5 - len(encoded)))
+
+
+def program_weights(
+    function_type: int, params: Sequence[int], bins: int
+) -> Tuple[int, ...]:
+    """Integer weights for d = 1..bins, bit-for-bit equal to the program's
+    `function_weight`. Results are scaled by PARAM_SCALE."""
+    if len(params) != 5:
+        raise ValueError("params must have 5 entries")
+    return tuple(
+        _program_weight(function_type, params, d, bins) for d in range(1, bins + 1)
+    )
+
+
+def preset_function_spec(preset: Mapping[str, float]) -> Tuple[int, Tuple[int, ...]]:
+    """Encode a README Param API object, e.g.
+    `{"type": "power", "p": 2.5, "base": 1, "amp": 120}`, for the program."""
+    kinds = {
+        "power": (FUNCTION_PRESET_POWER, "p"),
+        "exp": (FUNCTION_PRESET_EXP, "k"),
+        "log": (FUNCTION_PRESET_LOG, "a"),
+        "sigmoid": (FUNCTION_PRESET_SIGMOID, "k"),
+    }
+    kind = preset.get("type")
+    if kind not in kinds:
+        raise ValueError(f"unknown preset type: {kind}")
+    function_type, shape_name = kinds[kind]
+    values = (
+        preset.get("base", 0),
+        preset.get("amp", 0),
+        preset[shape_name],
+        preset.get("m", 0),
+        0,
+    )
+    return function_type, tuple(round(float(value) * PARAM_SCALE) for value in values)
+
+
+def program_allocation(total_amount: int, weights: Sequence[int]) -> Tuple[int, ...]:
+    """Split one side of a deposit exactly like the program's
+    `allocate_by_weights`: floor, then largest remainder, closest bin first."""
+    if total_amount == 0:
+        return tuple(0 for _ in weights)
+    if not weights:
+        raise ValueError("no bins for deposit")
+    if any(weight < 0 for weight in weights):
+        raise ValueError("weights must be non-negative")
+    total_weight = sum(weights)
+    if total_weight == 0:
+        raise ValueError("all weights are zero")
+
+    scaled = [total_amount * weight for weight in weights]
+    amounts = [value // total_weight for value in scaled]
+    fractions = [value % total_weight for value in scaled]
+    remainder = total_amount - sum(amounts)
+    for index in sorted(range(len(weights)), key=lambda i: (-fractions[i], i))[
+        :remainder
+    ]:
+        amounts[index] += 1
+    return tuple(amounts)
+
+
+def _program_weight(
+    function_type: int, params: Sequence[int], distance: int, max_distance: int
+) -> int:
+    if function_type == FUNCTION_LINEAR:
+        m, x0, y0 = params[0], params[1], params[2]
+        return _tdiv(m * (distance * PARAM_SCALE - x0), PARAM_SCALE) + y0
+
+    p0, p1, p2, p3, p4 = (value * (FIXED_ONE // PARAM_SCALE) for value in params)
+    d = distance * FIXED_ONE
+    if function_type == FUNCTION_LOG:
+        arg = _fixed_mul(p2, p3 - d)
+        weight = _fixed_mul(p0, _fixed_log_b(arg, p1)) + p4
+    elif function_type == FUNCTION_SPOT:
+        weight = FIXED_ONE
+    elif function_type == FUNCTION_CURVE:
+        weight = _fixed_gaussian(d, p0)
+    elif function_type == FUNCTION_BIDASK:
+        weight = FIXED_ONE + _fixed_mul(p1, FIXED_ONE - _fixed_gaussian(d, p0))
+    elif function_type == FUNCTION_EXPONENTIAL:
+        weight = _fixed_pow(p0, d)
+    elif function_type == FUNCTION_POWER:
+        weight = _fixed_div(FIXED_ONE, _fixed_pow(d + p1, p0))
+    elif function_type == FUNCTION_WALL_DECAY:
+        wall_bins = _tdiv(params[0], PARAM_SCALE)
+        if distance <= wall_bins:
+            weight = FIXED_ONE
+        else:
+            weight = _fixed_pow(p1, (distance - wall_bins) * FIXED_ONE)
+    elif function_type in (
+        FUNCTION_PRESET_POWER,
+        FUNCTION_PRESET_EXP,
+        FUNCTION_PRESET_LOG,
+        FUNCTION_PRESET_SIGMOID,
+    ):
+        x = 0
+        if max_distance > 1:
+            x = (distance - 1) * FIXED_ONE // (max_distance - 1)
+        weight = p0 + _fixed_mul(p1, _fixed_preset_curve(function_type, p2, p3, x))
+    else:
+        raise ValueError(f"unknown function type: {function_type}")
+    weight = _tdiv(weight, FIXED_ONE // PARAM_SCALE)
+    if not -(2**63) <= weight < 2**63:
+        raise OverflowError("weight does not fit in i64")
+    return weight
+
+
+def _tdiv(a: int, b: int) -> int:
+    # Rust integer division truncates toward zero.
+    quotient = abs(a) // abs(b)
+    return quotient if (a < 0) == (b < 0) else -quotient
+
+
+def _checked(value: int) -> int:
+    if not _I128_MIN <= value <= _I128_MAX:
+        raise OverflowError("i128 overflow")
+    return value
+
+
+def _fixed_mul(a: int, b: int) -> int:
+    return _tdiv(_checked(a * b), FIXED_ONE)
+
+
+def _fixed_div(a: int, b: int) -> int:
+    if b == 0:
+        raise ValueError("division by zero")
+    return _tdiv(_checked(a * FIXED_ONE), b)
+
+
+def _fixed_ln(x: int) -> int:
+    if x <= 0:
+        raise ValueError("ln of non-positive value")
+    k = x.bit_length() - FIXED_ONE.bit_length()
+    y = x >> k if k >= 0 else x << -k
+    if y >= 2 * FIXED_ONE:
+        k += 1
+    elif y < FIXED_ONE:
+        k -= 1
+    y = x >> k if k >= 0 else x << -k
+
+    z = (y - FIXED_ONE) * FIXED_ONE // (y + FIXED_ONE)
+    z2 = z * z // FIXED_ONE
+    term, series, n = z, 0, 1
+    while term != 0:
+        series += term // n
+        term = term * z2 // FIXED_ONE
+        n += 2
+    return k * FIXED_LN_2 + 2 * series
+
+
+def _fixed_log_b(x: int, base: int) -> int:
+    ln_base = _fixed_ln(base)
+    if ln_base == 0:
+        raise ValueError("log base must not be 1")
+    return _fixed_div(_fixed_ln(x), ln_base)
+
+
+def _fixed_exp(x: int) -> int:
+    if x > FIXED_EXP_MAX:
+        raise OverflowError("exp overflow")
+    if x < FIXED_EXP_MIN:
+        return 0
+    half = FIXED_LN_2 // 2 if x >= 0 else -(FIXED_LN_2 // 2)
+    k = _tdiv(x + half, FIXED_LN_2)
+    r = x - k * FIXED_LN_2
+
+    total, term, n = FIXED_ONE, FIXED_ONE, 1
+    while term != 0:
+        term = _tdiv(_tdiv(term * r, FIXED_ONE), n)
+        total += term
+        n += 1
+    if k >= 0:
+        return _checked(total * (1 << k))
+    return total >> -k
+
+
+def _fixed_pow(x: int, y: int) -> int:
+    if x < 0:
+        raise ValueError("pow of negative base")
+    if x == 0:
+        if y > 0:
+            return 0
+        if y == 0:
+            return FIXED_ONE
+        raise ValueError("zero to a negative power")
+    if y == 0:
+        return FIXED_ONE
+    return _fixed_exp(_fixed_mul(y, _fixed_ln(x)))
+
+
+def _fixed_preset_curve(function_type: int, shape: int, midpoint: int, x: int) -> int:
+    if function_type == FUNCTION_PRESET_POWER:
+        return _fixed_pow(x, shape)
+    if function_type == FUNCTION_PRESET_EXP:
+        numerator = _fixed_exp(_fixed_mul(shape, x))
+        denominator = _fixed_exp(shape)
+        return _fixed_div(numerator - FIXED_ONE, denominator - FIXED_ONE)
+    if function_type == FUNCTION_PRESET_LOG:
+        numerator = _fixed_ln(FIXED_ONE + _fixed_mul(shape, x))
+        return _fixed_div(numerator, _fixed_ln(FIXED_ONE + shape))
+
+    def logistic(t: int) -> int:
+        exponent = _fixed_mul(shape, midpoint - t)
+        return _fixed_div(FIXED_ONE, FIXED_ONE + _fixed_exp(exponent))
+
+    low, high = logistic(0), logistic(FIXED_ONE)
+    return _fixed_div(logistic(x) - low, high - low)
+
+
+def _fixed_gaussian(d: int, sigma: int) -> int:
+    offset = d - FIXED_ONE
+    numerator = _fixed_mul(offset, offset)
+    denominator = 2 * _fixed_mul(sigma, sigma)
+    return _fixed_exp(-_fixed_div(numerator, denominator))
+
+
 def preview_allocation(
     total_amount: int,
     left_weights: Sequence[float],
@@ -1,6 +1,12 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use anchor_spl::associated_token::AssociatedToken;
 use anchor_spl::token::{self, Mint, Token, TokenAccount};
+use std::cell::RefMut;
+use std::ops::{Deref, DerefMut};
+
+pub mod math;
+pub mod oracle;
 
 declare_id!("Orig1nDex111111111111111111111111111111111");
 
@@ -22,12 +28,70 @@ pub const ASSET_MASK_EUR_TOKEN: u16 = 1 << 3;
 pub const ASSET_MASK_FIAT_GOLD_PROXY: u16 = 1 << 4;
 pub const ASSET_MASK_COMMODITY_PROXY: u16 = 1 << 5;
 
+// Guard rails for `update_reference_prices`.
+pub const MAX_REFERENCE_PRICE_DEVIATION_BPS: u64 = 1_000;
+pub const MIN_REFERENCE_PRICE_UPDATE_SLOTS: u64 = 150;
+
+// One-sided deposits may leave their side of the active bin worth up to this
+// many bps of the other side. The default keeps both sides at least as deep.
+pub const DEFAULT_DEPTH_RATIO_BPS: u32 = 10_000;
+pub const MAX_DEPTH_RATIO_BPS: u32 = 100_000;
+
+pub const DEFAULT_FLASH_LOAN_FEE_BPS: u16 = 9;
+pub const MAX_FLASH_LOAN_FEE_BPS: u16 = 1_000;
+
+pub const ORACLE_KIND_NONE: u8 = 0;
+pub const ORACLE_KIND_PYTH_STYLE: u8 = 1;
+
 pub const HOUSE_FEE_REBATE_BPS: u16 = 0;
+// Share of the swap fee (in bps of `fee_bps`) that goes to the house.
+pub const DEFAULT_PROTOCOL_FEE_SHARE_BPS: u16 = 500;
+pub const MAX_PROTOCOL_FEE_SHARE_BPS: u16 = 5_000;
+
+// Variable fee (DLMM style): fee_bps + control * va^2 / VARIABLE_FEE_PRECISION,
+// where the volatility accumulator `va` grows by VOLATILITY_SCALE per bin
+// crossed away from the reference bin.
+pub const VOLATILITY_SCALE: u32 = 10_000;
+pub const VARIABLE_FEE_PRECISION: u128 = 1_000_000_000_000;
+pub const MAX_TOTAL_FEE_BPS: u16 = 5_000;
+pub const DEFAULT_FILTER_PERIOD_SLOTS: u16 = 30;
+pub const DEFAULT_DECAY_PERIOD_SLOTS: u16 = 600;
+pub const DEFAULT_REDUCTION_FACTOR_BPS: u16 = 5_000;
+pub const DEFAULT_MAX_VOLATILITY_ACCUMULATOR: u32 = 350_000;
 
 pub const FUNCTION_LINEAR: u8 = 1;
 pub const FUNCTION_LOG: u8 = 2;
+pub const FUNCTION_SPOT: u8 = 3;
+pub const FUNCTION_CURVE: u8 = 4;
+pub const FUNCTION_BIDASK: u8 = 5;
+pub const FUNCTION_EXPONENTIAL: u8 = 6;
+pub const FUNCTION_POWER: u8 = 7;
+pub const FUNCTION_WALL_DECAY: u8 = 8;
+// Normalized presets: weight(d) = base + amp * f(x), x = (d - 1) / (maxD - 1).
+// params: [base, amp, shape, midpoint, unused]
+pub const FUNCTION_PRESET_POWER: u8 = 9;
+pub const FUNCTION_PRESET_EXP: u8 = 10;
+pub const FUNCTION_PRESET_LOG: u8 = 11;
+pub const FUNCTION_PRESET_SIGMOID: u8 = 12;
 
 pub const PARAM_SCALE: i64 = 1_000_000;
+pub const PRESET_MAX_WEIGHT: i64 = 1_000_000 * PARAM_SCALE;
+pub const PRESET_MAX_STEEPNESS: i64 = 40 * PARAM_SCALE;
+pub const PRESET_MAX_LOG_SHAPE: i64 = 1_000_000 * PARAM_SCALE;
+
+// Bin prices are geometric: price(bin_id) = (1 + bin_step_bps / 10_000)^bin_id,
+// token B per token A in Q64.64.
+pub const Q64: u128 = 1 << 64;
+pub const MAX_BIN_STEP_BPS: u16 = 1_000;
+pub const MAX_BIN_ID: i32 = 443_636;
+
+pub const BIN_ARRAY_SIZE: usize = 70;
+pub const MAX_BINS_PER_DEPOSIT: usize = 64;
+pub const MAX_BINS_PER_SWAP: usize = 32;
+pub const MAX_ROUTE_HOPS: usize = 4;
+pub const MAX_OBSERVATIONS: u16 = 1_024;
+
+pub const BPS_DENOMINATOR: u64 = 10_000;
 
 #[program]
 pub mod origin_dex {
@@ -41,13 +105,57 @@ pub mod origin_dex {
         config.admin = ctx.accounts.admin.key();
         config.bump = *ctx.bumps.get("config").ok_or(DexError::MissingBump)?;
         config.initialized = true;
+        config.treasury = ctx.accounts.admin.key();
+        config.protocol_fee_share_bps = DEFAULT_PROTOCOL_FEE_SHARE_BPS;
+
+        emit_cpi!(ConfigInitialized {
+            config: config.key(),
+            admin: config.admin,
+        });
         Ok(())
     }
 
     pub fn set_admin(ctx: Context<SetAdmin>, new_admin: Pubkey) -> Result<()> {
         let config = &mut ctx.accounts.config;
         require_keys_eq!(config.admin, ctx.accounts.admin.key(), DexError::Unauthorized);
+        let old_admin = config.admin;
         config.admin = new_admin;
+
+        emit_cpi!(AdminUpdated {
+            old_admin,
+            new_admin,
+        });
+        Ok(())
+    }
+
+    pub fn set_treasury(ctx: Context<SetTreasury>, new_treasury: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require_keys_eq!(config.admin, ctx.accounts.admin.key(), DexError::Unauthorized);
+        let old_treasury = config.treasury;
+        config.treasury = new_treasury;
+
+        emit_cpi!(TreasuryUpdated {
+            old_treasury,
+            new_treasury,
+        });
+        Ok(())
+    }
+
+    /// Sets the house share applied to pools created from now on.
+    pub fn set_protocol_fee_share(
+        ctx: Context<SetProtocolFeeShare>,
+        protocol_fee_share_bps: u16,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require_keys_eq!(config.admin, ctx.accounts.admin.key(), DexError::Unauthorized);
+        validate_protocol_fee_share(protocol_fee_share_bps)?;
+
+        let old_share_bps = config.protocol_fee_share_bps;
+        config.protocol_fee_share_bps = protocol_fee_share_bps;
+        emit_cpi!(ProtocolFeeShareUpdated {
+            old_share_bps,
+            new_share_bps: protocol_fee_share_bps,
+        });
         Ok(())
     }
 
@@ -60,14 +168,20 @@ pub mod origin_dex {
         registry.bump = *ctx.bumps.get("registry").ok_or(DexError::MissingBump)?;
         registry.next_pool_id = 0;
         registry.initialized = true;
+
+        emit_cpi!(RegistryInitialized {
+            registry: registry.key(),
+            admin: registry.admin,
+        });
         Ok(())
     }
 
     pub fn create_pool(
         ctx: Context<CreatePool>,
         fee_bps: u16,
-        token_a_price_cents: u64,
-        token_b_price_cents: u64,
+        bin_step_bps: u16,
+        token_a_price: u128,
+        token_b_price: u128,
         token_a_kind: u8,
         token_b_kind: u8,
         guarantee_policy: u8,
@@ -80,6 +194,9 @@ pub mod origin_dex {
         if fee_bps > 10_000 {
             return err!(DexError::InvalidFee);
         }
+        if bin_step_bps == 0 || bin_step_bps > MAX_BIN_STEP_BPS {
+            return err!(DexError::InvalidBinStep);
+        }
 
         validate_token_kind(
             token_a_kind,
@@ -94,8 +211,15 @@ pub mod origin_dex {
 
         validate_guarantee_policy(guarantee_policy, allowed_assets_mask, guarantee_mint)?;
 
-        let bin_spacing_milli_cents =
-            compute_bin_spacing_milli_cents(token_a_price_cents, token_b_price_cents)?;
+        let token_a_decimals = ctx.accounts.token_a_mint.decimals;
+        let token_b_decimals = ctx.accounts.token_b_mint.decimals;
+        let active_bin_id = bin_id_from_price(
+            base_unit_price(
+                (token_a_price, token_b_price),
+                (token_a_decimals, token_b_decimals),
+            )?,
+            bin_step_bps,
+        )?;
 
         let pool = &mut ctx.accounts.pool;
         pool.pool_id = registry.next_pool_id;
@@ -107,46 +231,163 @@ pub mod origin_dex {
         pool.token_a_frozen = ctx.accounts.token_a_mint.freeze_authority.is_some();
         pool.token_b_frozen = ctx.accounts.token_b_mint.freeze_authority.is_some();
         pool.fee_bps = fee_bps;
-        pool.house_fee_bps = fee_bps.saturating_mul(5) / 100;
-        pool.lp_fee_bps = pool.fee_bps.saturating_sub(pool.house_fee_bps);
-        pool.bin_spacing_milli_cents = bin_spacing_milli_cents;
+        pool.protocol_fee_share_bps = ctx.accounts.config.protocol_fee_share_bps;
+        (pool.house_fee_bps, pool.lp_fee_bps) = split_fee_bps(fee_bps, pool.protocol_fee_share_bps);
+        pool.bin_step_bps = bin_step_bps;
         pool.guarantee_policy = guarantee_policy;
         pool.allowed_assets_mask = allowed_assets_mask;
         pool.guarantee_mint = guarantee_mint;
-        pool.token_a_price_cents = token_a_price_cents;
-        pool.token_b_price_cents = token_b_price_cents;
+        pool.token_a_price = token_a_price;
+        pool.token_b_price = token_b_price;
         pool.total_a_amount = 0;
         pool.total_b_amount = 0;
         pool.vault_a = ctx.accounts.vault_a.key();
         pool.vault_b = ctx.accounts.vault_b.key();
         pool.next_position_id = 0;
         pool.bump = *ctx.bumps.get("pool").ok_or(DexError::MissingBump)?;
+        pool.active_bin_id = active_bin_id;
+        pool.house_fees_a = 0;
+        pool.house_fees_b = 0;
+        // The variable fee stays off until the admin sets a fee control.
+        pool.dynamic_fee = DynamicFee {
+            filter_period: DEFAULT_FILTER_PERIOD_SLOTS,
+            decay_period: DEFAULT_DECAY_PERIOD_SLOTS,
+            reduction_factor: DEFAULT_REDUCTION_FACTOR_BPS,
+            max_volatility_accumulator: DEFAULT_MAX_VOLATILITY_ACCUMULATOR,
+            max_total_fee_bps: fee_bps,
+            ..DynamicFee::default()
+        };
+        pool.oracle_kind = ORACLE_KIND_NONE;
+        pool.oracle_a = Pubkey::default();
+        pool.oracle_b = Pubkey::default();
+        pool.max_oracle_age_secs = 0;
+        pool.max_oracle_confidence_bps = 0;
+        pool.last_price_update_slot = 0;
+        pool.token_a_decimals = token_a_decimals;
+        pool.token_b_decimals = token_b_decimals;
+        pool.depth_ratio_above_bps = DEFAULT_DEPTH_RATIO_BPS;
+        pool.depth_ratio_below_bps = DEFAULT_DEPTH_RATIO_BPS;
+        pool.flash_loan_fee_bps = DEFAULT_FLASH_LOAN_FEE_BPS;
+        pool.flash_loan_amount = 0;
+        pool.flash_loan_fee = 0;
+        pool.flash_loan_in_a = false;
+        pool.next_order_id = 0;
         registry.next_pool_id = registry
             .next_pool_id
             .checked_add(1)
             .ok_or(DexError::Overflow)?;
+
+        emit_cpi!(PoolCreated {
+            pool: pool.key(),
+            pool_id: pool.pool_id,
+            creator: pool.creator,
+            token_a_mint: pool.token_a_mint,
+            token_b_mint: pool.token_b_mint,
+            fee_bps,
+            bin_step_bps,
+            active_bin_id,
+        });
+        Ok(())
+    }
+
+    pub fn initialize_bin_array(ctx: Context<InitializeBinArray>, index: i64) -> Result<()> {
+        // Zero-copy accounts start zeroed, so every bin starts empty.
+        let mut bin_array = ctx.accounts.bin_array.load_init()?;
+        bin_array.pool = ctx.accounts.pool.key();
+        bin_array.index = index;
+        bin_array.bump = *ctx.bumps.get("bin_array").ok_or(DexError::MissingBump)?;
+
+        emit_cpi!(BinArrayInitialized {
+            pool: bin_array.pool,
+            bin_array: ctx.accounts.bin_array.key(),
+            index,
+        });
+        Ok(())
+    }
+
+    /// Creates the pool's TWAP observation buffer with room for one
+    /// observation. Swaps require it; anyone may pay for it.
+    pub fn initialize_observations(ctx: Context<InitializeObservations>) -> Result<()> {
+        let observations = &mut ctx.accounts.observations;
+        observations.pool = ctx.accounts.pool.key();
+        observations.index = 0;
+        observations.bump = *ctx.bumps.get("observations").ok_or(DexError::MissingBump)?;
+        observations.observations = vec![Observation {
+            slot: Clock::get()?.slot,
+            cumulative_price: 0,
+        }];
+
+        emit_cpi!(ObservationsInitialized {
+            pool: observations.pool,
+            observations: observations.key(),
+        });
+        Ok(())
+    }
+
+    /// Grows the observation buffer to `capacity` entries so TWAPs can
+    /// reach further back. The payer funds the extra rent.
+    pub fn grow_observations(ctx: Context<GrowObservations>, capacity: u16) -> Result<()> {
+        let observations = &mut ctx.accounts.observations;
+        if capacity as usize <= observations.observations.len() || capacity > MAX_OBSERVATIONS {
+            return err!(DexError::InvalidObservationCapacity);
+        }
+        let old_capacity = observations.observations.len() as u16;
+        observations.grow(capacity as usize);
+
+        emit_cpi!(ObservationsGrown {
+            pool: observations.pool,
+            old_capacity,
+            new_capacity: capacity,
+        });
         Ok(())
     }
 
+    /// Time-weighted average of the active bin price over the last
+    /// `window_slots` slots, Q64.64 token B per token A in base units.
+    pub fn twap(ctx: Context<Twap>, window_slots: u64) -> Result<u128> {
+        let pool = &ctx.accounts.pool;
+        let current_price = bin_price(pool, pool.active_bin_id)?;
+        ctx.accounts
+            .observations
+            .twap(Clock::get()?.slot, window_slots, current_price)
+    }
+
     pub fn create_lp_position(
         ctx: Context<CreateLpPosition>,
-        min_price_cents: u64,
-        max_price_cents: u64,
+        min_price: u128,
+        max_price: u128,
         left_function_type: u8,
         left_params: [i64; 5],
         right_function_type: u8,
         right_params: [i64; 5],
         amount_a: u64,
         amount_b: u64,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
+        expires_at_slot: u64,
     ) -> Result<()> {
+        check_deadline(expires_at_slot)?;
         let pool = &mut ctx.accounts.pool;
-        if min_price_cents >= max_price_cents {
+        check_no_flash_loan(pool)?;
+        check_active_bin_slippage(pool, expected_active_bin_id, max_active_bin_slippage)?;
+        if min_price >= max_price {
             return err!(DexError::InvalidPriceRange);
         }
 
         validate_function_spec(left_function_type, &left_params)?;
         validate_function_spec(right_function_type, &right_params)?;
 
+        let prices = current_prices(
+            pool,
+            ctx.accounts.oracle_a.as_deref(),
+            ctx.accounts.oracle_b.as_deref(),
+        )?;
+        let lower_bin_id = range_bin_id(pool, min_price, prices.1)?;
+        let upper_bin_id = range_bin_id(pool, max_price, prices.1)?;
+        let mut bin_arrays =
+            load_position_bin_arrays(&ctx.accounts.bin_array_lower, &ctx.accounts.bin_array_upper)?;
+        validate_position_bins(lower_bin_id, upper_bin_id, &bin_arrays)?;
+
         let position = &mut ctx.accounts.position;
         require_keys_eq!(
             position.pool,
@@ -157,8 +398,8 @@ pub mod origin_dex {
         position.owner = ctx.accounts.owner.key();
         position.position_id = pool.next_position_id;
         position.lp_mint = ctx.accounts.lp_mint.key();
-        position.min_price_cents = min_price_cents;
-        position.max_price_cents = max_price_cents;
+        position.min_price = min_price;
+        position.max_price = max_price;
         position.left_function_type = left_function_type;
         position.right_function_type = right_function_type;
         position.left_params = left_params;
@@ -166,6 +407,14 @@ pub mod origin_dex {
         position.amount_a = amount_a;
         position.amount_b = amount_b;
         position.bump = *ctx.bumps.get("position").ok_or(DexError::MissingBump)?;
+        position.lower_bin_id = lower_bin_id;
+        position.upper_bin_id = upper_bin_id;
+        position.liquidity_shares = [0; MAX_BINS_PER_DEPOSIT];
+        position.fee_growth_checkpoints_a = [0; MAX_BINS_PER_DEPOSIT];
+        position.fee_growth_checkpoints_b = [0; MAX_BINS_PER_DEPOSIT];
+        position.fees_owed_a = 0;
+        position.fees_owed_b = 0;
+        validate_position_functions(pool.active_bin_id, position)?;
 
         transfer_deposit(
             &ctx.accounts.token_program,
@@ -178,7 +427,8 @@ pub mod origin_dex {
             amount_b,
         )?;
 
-        apply_liquidity(pool, amount_a, amount_b)?;
+        apply_liquidity(pool, &bin_arrays, amount_a, amount_b, prices)?;
+        deposit_to_bins(pool, position, &mut bin_arrays, amount_a, amount_b)?;
 
         pool.next_position_id = pool
             .next_position_id
@@ -221,6 +471,16 @@ pub mod origin_dex {
             None,
         )?;
 
+        emit_cpi!(PositionCreated {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            owner: ctx.accounts.owner.key(),
+            lp_mint: ctx.accounts.lp_mint.key(),
+            lower_bin_id,
+            upper_bin_id,
+            amount_a,
+            amount_b,
+        });
         Ok(())
     }
 
@@ -251,6 +511,11 @@ pub mod origin_dex {
             1,
         )?;
 
+        emit_cpi!(LpNftStaked {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            owner: ctx.accounts.owner.key(),
+        });
         Ok(())
     }
 
@@ -281,6 +546,11 @@ pub mod origin_dex {
             1,
         )?;
 
+        emit_cpi!(LpNftUnstaked {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            owner: ctx.accounts.owner.key(),
+        });
         Ok(())
     }
 
@@ -288,12 +558,20 @@ pub mod origin_dex {
         ctx: Context<AddLiquidityToPosition>,
         amount_a: u64,
         amount_b: u64,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
+        expires_at_slot: u64,
     ) -> Result<()> {
         require_keys_eq!(ctx.accounts.position.pool, ctx.accounts.pool.key(), DexError::InvalidPosition);
         require_keys_eq!(ctx.accounts.position.owner, ctx.accounts.owner.key(), DexError::Unauthorized);
+        check_deadline(expires_at_slot)?;
 
         let pool = &mut ctx.accounts.pool;
+        check_no_flash_loan(pool)?;
+        check_active_bin_slippage(pool, expected_active_bin_id, max_active_bin_slippage)?;
         let position = &mut ctx.accounts.position;
+        let mut bin_arrays =
+            load_position_bin_arrays(&ctx.accounts.bin_array_lower, &ctx.accounts.bin_array_upper)?;
 
         transfer_deposit(
             &ctx.accounts.token_program,
@@ -306,7 +584,13 @@ pub mod origin_dex {
             amount_b,
         )?;
 
-        apply_liquidity(pool, amount_a, amount_b)?;
+        let prices = current_prices(
+            pool,
+            ctx.accounts.oracle_a.as_deref(),
+            ctx.accounts.oracle_b.as_deref(),
+        )?;
+        apply_liquidity(pool, &bin_arrays, amount_a, amount_b, prices)?;
+        deposit_to_bins(pool, position, &mut bin_arrays, amount_a, amount_b)?;
         position.amount_a = position
             .amount_a
             .checked_add(amount_a)
@@ -334,15 +618,167 @@ pub mod origin_dex {
             1,
         )?;
 
+        emit_cpi!(LiquidityAdded {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    pub fn remove_liquidity(
+        ctx: Context<RemoveLiquidity>,
+        bps: u16,
+        min_amount_a: u64,
+        min_amount_b: u64,
+        expires_at_slot: u64,
+    ) -> Result<()> {
+        require_keys_eq!(ctx.accounts.position.pool, ctx.accounts.pool.key(), DexError::InvalidPosition);
+        // Liquidity follows the LP NFT, and stays put while it is staked.
+        if ctx.accounts.owner_lp_token_account.amount == 0 {
+            return err!(DexError::Unauthorized);
+        }
+        check_not_staked(&ctx.accounts.stake)?;
+        check_deadline(expires_at_slot)?;
+        if bps == 0 || bps as u64 > BPS_DENOMINATOR {
+            return err!(DexError::InvalidAmount);
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        check_no_flash_loan(pool)?;
+        let (amount_a, amount_b) = withdraw_position(
+            pool,
+            &mut ctx.accounts.position,
+            &mut load_position_bin_arrays(
+                &ctx.accounts.bin_array_lower,
+                &ctx.accounts.bin_array_upper,
+            )?,
+            bps,
+        )?;
+        if amount_a < min_amount_a || amount_b < min_amount_b {
+            return err!(DexError::SlippageExceeded);
+        }
+
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.owner_token_a,
+            amount_a,
+        )?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.owner_token_b,
+            amount_b,
+        )?;
+
+        emit_cpi!(LiquidityRemoved {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            bps,
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    pub fn claim_fees(ctx: Context<ClaimFees>) -> Result<()> {
+        require_keys_eq!(ctx.accounts.position.pool, ctx.accounts.pool.key(), DexError::InvalidPosition);
+        // Fees follow the LP NFT, not the original depositor.
+        if ctx.accounts.holder_lp_token_account.amount == 0 {
+            return err!(DexError::Unauthorized);
+        }
+
+        let position = &mut ctx.accounts.position;
+        let mut bin_arrays = vec![ctx.accounts.bin_array_lower.load()?];
+        if ctx.accounts.bin_array_upper.key() != ctx.accounts.bin_array_lower.key() {
+            bin_arrays.push(ctx.accounts.bin_array_upper.load()?);
+        }
+        validate_position_bins(position.lower_bin_id, position.upper_bin_id, &bin_arrays)?;
+        for bin_id in position.lower_bin_id..=position.upper_bin_id {
+            let bin_array = bin_arrays
+                .iter()
+                .find(|bin_array| bin_array.contains(bin_id))
+                .ok_or(DexError::InvalidBinArray)?;
+            position.accrue_fees(bin_id, bin_array.bin(bin_id)?)?;
+        }
+
+        let (fees_a, fees_b) = (position.fees_owed_a, position.fees_owed_b);
+        position.fees_owed_a = 0;
+        position.fees_owed_b = 0;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.pool,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.holder_token_a,
+            fees_a,
+        )?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.pool,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.holder_token_b,
+            fees_b,
+        )?;
+
+        emit_cpi!(FeesClaimed {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            holder: ctx.accounts.holder.key(),
+            amount_a: fees_a,
+            amount_b: fees_b,
+        });
         Ok(())
     }
 
     pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
         require_keys_eq!(ctx.accounts.position.pool, ctx.accounts.pool.key(), DexError::InvalidPosition);
-        require_keys_eq!(ctx.accounts.position.owner, ctx.accounts.owner.key(), DexError::Unauthorized);
-        if ctx.accounts.stake.active {
-            return err!(DexError::AlreadyStaked);
+        // The LP NFT holder closes the position and receives its fees, as
+        // with `claim_fees`.
+        if ctx.accounts.owner_lp_token_account.amount == 0 {
+            return err!(DexError::Unauthorized);
         }
+        check_not_staked(&ctx.accounts.stake)?;
+
+        // Return everything the position still holds, including unclaimed
+        // fees, before it is closed.
+        let pool = &mut ctx.accounts.pool;
+        check_no_flash_loan(pool)?;
+        let position = &mut ctx.accounts.position;
+        let (amount_a, amount_b) = withdraw_position(
+            pool,
+            position,
+            &mut load_position_bin_arrays(
+                &ctx.accounts.bin_array_lower,
+                &ctx.accounts.bin_array_upper,
+            )?,
+            BPS_DENOMINATOR as u16,
+        )?;
+        let amount_a = amount_a
+            .checked_add(position.fees_owed_a)
+            .ok_or(DexError::Overflow)?;
+        let amount_b = amount_b
+            .checked_add(position.fees_owed_b)
+            .ok_or(DexError::Overflow)?;
+        position.fees_owed_a = 0;
+        position.fees_owed_b = 0;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.owner_token_a,
+            amount_a,
+        )?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.owner_token_b,
+            amount_b,
+        )?;
 
         token::burn(
             CpiContext::new(
@@ -365,500 +801,3063 @@ pub mod origin_dex {
             },
         ))?;
 
+        emit_cpi!(PositionClosed {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            owner: ctx.accounts.owner.key(),
+            amount_a,
+            amount_b,
+        });
         Ok(())
     }
-}
-
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + Config::SIZE,
-        seeds = [b"config"],
-        bump
-    )]
-    pub config: Account<'info, Config>,
 
-    #[account(mut)]
-    pub admin: Signer<'info>,
+    /// Rests `amount` of one token in `bin_id` until swaps fill it at the bin
+    /// price: token A above the active bin (bought as the price rises through
+    /// it) or token B below it. Filled parts never convert back.
+    pub fn place_limit_order(
+        ctx: Context<PlaceLimitOrder>,
+        bin_id: i32,
+        sell_a: bool,
+        amount: u64,
+        expires_at_slot: u64,
+    ) -> Result<()> {
+        check_deadline(expires_at_slot)?;
+        if amount == 0 {
+            return err!(DexError::InvalidAmount);
+        }
+        let pool = &mut ctx.accounts.pool;
+        let away_from_active = if sell_a {
+            bin_id > pool.active_bin_id
+        } else {
+            bin_id < pool.active_bin_id
+        };
+        if !away_from_active {
+            return err!(DexError::InvalidLimitOrderBin);
+        }
+        let (vault_in, _) = order_vaults(pool, sell_a);
+        require_keys_eq!(ctx.accounts.vault.key(), vault_in, DexError::InvalidVault);
 
-    pub system_program: Program<'info, System>,
-}
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let bin = bin_array.bin_mut(bin_id)?;
+        let (shares, round, amount) = bin.add_order(sell_a, amount)?;
+        let order = &mut ctx.accounts.order;
+        order.pool = pool.key();
+        order.owner = ctx.accounts.owner.key();
+        order.order_id = pool.next_order_id;
+        order.bin_id = bin_id;
+        order.sell_a = sell_a;
+        order.round = round;
+        order.shares = shares;
+        order.amount = amount;
+        order.filled = 0;
+        order.bump = *ctx.bumps.get("order").ok_or(DexError::MissingBump)?;
+        pool.next_order_id = pool
+            .next_order_id
+            .checked_add(1)
+            .ok_or(DexError::Overflow)?;
 
-#[derive(Accounts)]
-pub struct SetAdmin<'info> {
-    #[account(
-        mut,
-        seeds = [b"config"],
-        bump = config.bump,
-    )]
-    pub config: Account<'info, Config>,
+        transfer_to_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.owner,
+            &ctx.accounts.owner_token,
+            &ctx.accounts.vault,
+            amount,
+        )?;
 
-    pub admin: Signer<'info>,
-}
+        emit_cpi!(LimitOrderPlaced {
+            pool: ctx.accounts.pool.key(),
+            order: ctx.accounts.order.key(),
+            owner: ctx.accounts.owner.key(),
+            bin_id,
+            sell_a,
+            amount,
+        });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct InitRegistry<'info> {
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + Registry::SIZE,
-        seeds = [b"registry"],
-        bump
-    )]
-    pub registry: Account<'info, Registry>,
+    /// Pays out what the order's fills since the last claim earned. The
+    /// unfilled part keeps resting in the bin.
+    pub fn claim_filled_order(ctx: Context<ClaimFilledOrder>, expires_at_slot: u64) -> Result<()> {
+        let order = &mut ctx.accounts.order;
+        require_keys_eq!(
+            order.owner,
+            ctx.accounts.owner.key(),
+            DexError::Unauthorized
+        );
+        check_deadline(expires_at_slot)?;
+        let pool = &ctx.accounts.pool;
+        let (_, vault_out) = order_vaults(pool, order.sell_a);
+        require_keys_eq!(ctx.accounts.vault.key(), vault_out, DexError::InvalidVault);
 
-    #[account(mut)]
-    pub admin: Signer<'info>,
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let bin = bin_array.bin_mut(order.bin_id)?;
+        let proceeds = order.settle_fills(pool, bin)?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault,
+            &ctx.accounts.owner_token,
+            proceeds,
+        )?;
 
-    pub system_program: Program<'info, System>,
-}
+        emit_cpi!(LimitOrderClaimed {
+            pool: pool.key(),
+            order: order.key(),
+            owner: order.owner,
+            filled: order.filled,
+            proceeds,
+        });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct CreatePool<'info> {
-    #[account(
-        mut,
-        seeds = [b"registry"],
-        bump = registry.bump,
-    )]
-    pub registry: Account<'info, Registry>,
+    /// Closes a limit order, returning its unfilled input and unclaimed
+    /// proceeds.
+    pub fn cancel_limit_order(ctx: Context<CancelLimitOrder>, expires_at_slot: u64) -> Result<()> {
+        let order = &mut ctx.accounts.order;
+        require_keys_eq!(
+            order.owner,
+            ctx.accounts.owner.key(),
+            DexError::Unauthorized
+        );
+        check_deadline(expires_at_slot)?;
+        let pool = &ctx.accounts.pool;
+        let (vault_in, vault_out) = order_vaults(pool, order.sell_a);
+        require_keys_eq!(
+            ctx.accounts.vault_in.key(),
+            vault_in,
+            DexError::InvalidVault
+        );
+        require_keys_eq!(
+            ctx.accounts.vault_out.key(),
+            vault_out,
+            DexError::InvalidVault
+        );
 
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + Pool::SIZE,
-        seeds = [b"pool", &registry.next_pool_id.to_le_bytes()],
-        bump
-    )]
-    pub pool: Account<'info, Pool>,
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let bin = bin_array.bin_mut(order.bin_id)?;
+        let proceeds = order.settle_fills(pool, bin)?;
+        let refund = order.withdraw_unfilled(bin)?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_in,
+            &ctx.accounts.owner_token_in,
+            refund,
+        )?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_out,
+            &ctx.accounts.owner_token_out,
+            proceeds,
+        )?;
 
-    pub token_a_mint: Account<'info, Mint>,
-    pub token_b_mint: Account<'info, Mint>,
+        emit_cpi!(LimitOrderCancelled {
+            pool: pool.key(),
+            order: order.key(),
+            owner: order.owner,
+            refund,
+            proceeds,
+        });
+        Ok(())
+    }
 
-    #[account(
-        init,
-        payer = admin,
-        associated_token::mint = token_a_mint,
-        associated_token::authority = pool
-    )]
-    pub vault_a: Account<'info, TokenAccount>,
+    pub fn swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+        swap_for_b: bool,
+        expires_at_slot: u64,
+    ) -> Result<u64> {
+        check_deadline(expires_at_slot)?;
+        if amount_in == 0 {
+            return err!(DexError::InvalidAmount);
+        }
 
-    #[account(
-        init,
-        payer = admin,
-        associated_token::mint = token_b_mint,
-        associated_token::authority = pool
-    )]
-    pub vault_b: Account<'info, TokenAccount>,
+        let result = execute_swap(
+            ctx.accounts,
+            ctx.remaining_accounts,
+            SwapMode::ExactIn(amount_in),
+            swap_for_b,
+        )?;
+        if result.amount_out < min_amount_out {
+            return err!(DexError::SlippageExceeded);
+        }
 
-    #[account(mut)]
-    pub admin: Signer<'info>,
+        emit_cpi!(Swapped {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.user.key(),
+            swap_for_b,
+            amount_in: result.amount_in,
+            amount_out: result.amount_out,
+            active_bin_id: ctx.accounts.pool.active_bin_id,
+        });
+        Ok(result.amount_out)
+    }
 
-    pub system_program: Program<'info, System>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub token_program: Program<'info, Token>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    pub fn swap_exact_out<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+        amount_out: u64,
+        max_amount_in: u64,
+        swap_for_b: bool,
+        expires_at_slot: u64,
+    ) -> Result<u64> {
+        check_deadline(expires_at_slot)?;
+        if amount_out == 0 {
+            return err!(DexError::InvalidAmount);
+        }
 
-#[derive(Accounts)]
-pub struct CreateLpPosition<'info> {
-    #[account(
-        mut,
-        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
-        bump = pool.bump,
-    )]
-    pub pool: Account<'info, Pool>,
+        let result = execute_swap(
+            ctx.accounts,
+            ctx.remaining_accounts,
+            SwapMode::ExactOut(amount_out),
+            swap_for_b,
+        )?;
+        if result.amount_in > max_amount_in {
+            return err!(DexError::MaxAmountInExceeded);
+        }
 
-    #[account(
-        init,
-        payer = owner,
-        space = 8 + Position::SIZE,
-        seeds = [b"position", pool.key().as_ref(), &pool.next_position_id.to_le_bytes()],
-        bump
-    )]
-    pub position: Account<'info, Position>,
+        emit_cpi!(Swapped {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.user.key(),
+            swap_for_b,
+            amount_in: result.amount_in,
+            amount_out: result.amount_out,
+            active_bin_id: ctx.accounts.pool.active_bin_id,
+        });
+        Ok(result.amount_in)
+    }
 
-    #[account(
-        init,
-        payer = owner,
-        mint::decimals = 0,
-        mint::authority = position,
-        seeds = [b"lp_mint", position.key().as_ref()],
-        bump
-    )]
-    pub lp_mint: Account<'info, Mint>,
+    /// Swaps `amount_in` through an ordered list of pools, feeding each hop's
+    /// output into the next. Remaining accounts hold, per hop, the pool, its
+    /// observations, its input and output vaults, then
+    /// `bin_array_counts[hop]` bin arrays.
+    pub fn swap_route<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SwapRoute<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+        bin_array_counts: Vec<u8>,
+        expires_at_slot: u64,
+    ) -> Result<u64> {
+        check_deadline(expires_at_slot)?;
+        if amount_in == 0 {
+            return err!(DexError::InvalidAmount);
+        }
 
-    #[account(
-        init_if_needed,
-        payer = owner,
-        associated_token::mint = lp_mint,
-        associated_token::authority = owner
-    )]
-    pub owner_lp_token_account: Account<'info, TokenAccount>,
+        let amount_out = execute_route(
+            ctx.accounts,
+            ctx.remaining_accounts,
+            amount_in,
+            &bin_array_counts,
+        )?;
+        if amount_out < min_amount_out {
+            return err!(DexError::SlippageExceeded);
+        }
 
-    #[account(mut)]
-    pub owner_token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub owner_token_b: Account<'info, TokenAccount>,
+        emit_cpi!(RouteSwapped {
+            user: ctx.accounts.user.key(),
+            mint_in: ctx.accounts.user_token_in.mint,
+            mint_out: ctx.accounts.user_token_out.mint,
+            amount_in,
+            amount_out,
+            hops: bin_array_counts.len() as u8,
+        });
+        Ok(amount_out)
+    }
 
-    #[account(
-        mut,
-        associated_token::mint = token_a_mint,
-        associated_token::authority = pool
-    )]
-    pub vault_a: Account<'info, TokenAccount>,
-    #[account(
-        mut,
-        associated_token::mint = token_b_mint,
-        associated_token::authority = pool
-    )]
-    pub vault_b: Account<'info, TokenAccount>,
+    pub fn withdraw_house_fees(ctx: Context<WithdrawHouseFees>) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
 
-    pub token_a_mint: Account<'info, Mint>,
-    pub token_b_mint: Account<'info, Mint>,
+        let pool = &mut ctx.accounts.pool;
+        let (amount_a, amount_b) = (pool.house_fees_a, pool.house_fees_b);
+        pool.house_fees_a = 0;
+        pool.house_fees_b = 0;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.treasury_token_a,
+            amount_a,
+        )?;
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.treasury_token_b,
+            amount_b,
+        )?;
 
-    #[account(mut)]
-    pub owner: Signer<'info>,
+        emit_cpi!(HouseFeesWithdrawn {
+            pool: pool.key(),
+            treasury: ctx.accounts.config.treasury,
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+    /// Overrides the house share for one pool and recomputes its fee split.
+    pub fn set_pool_protocol_fee_share(
+        ctx: Context<SetPoolProtocolFeeShare>,
+        protocol_fee_share_bps: u16,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+        validate_protocol_fee_share(protocol_fee_share_bps)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let old_share_bps = pool.protocol_fee_share_bps;
+        pool.protocol_fee_share_bps = protocol_fee_share_bps;
+        (pool.house_fee_bps, pool.lp_fee_bps) = split_fee_bps(pool.fee_bps, protocol_fee_share_bps);
+
+        emit_cpi!(PoolProtocolFeeShareUpdated {
+            pool: pool.key(),
+            old_share_bps,
+            new_share_bps: protocol_fee_share_bps,
+            house_fee_bps: pool.house_fee_bps,
+            lp_fee_bps: pool.lp_fee_bps,
+        });
+        Ok(())
+    }
+
+    pub fn set_dynamic_fee_params(
+        ctx: Context<SetDynamicFeeParams>,
+        filter_period: u16,
+        decay_period: u16,
+        reduction_factor: u16,
+        variable_fee_control: u32,
+        max_volatility_accumulator: u32,
+        max_total_fee_bps: u16,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        if filter_period >= decay_period
+            || reduction_factor as u64 > BPS_DENOMINATOR
+            || max_total_fee_bps < pool.fee_bps
+            || max_total_fee_bps > MAX_TOTAL_FEE_BPS
+        {
+            return err!(DexError::InvalidDynamicFeeParams);
+        }
+
+        let dynamic_fee = &mut pool.dynamic_fee;
+        dynamic_fee.filter_period = filter_period;
+        dynamic_fee.decay_period = decay_period;
+        dynamic_fee.reduction_factor = reduction_factor;
+        dynamic_fee.variable_fee_control = variable_fee_control;
+        dynamic_fee.max_volatility_accumulator = max_volatility_accumulator;
+        dynamic_fee.max_total_fee_bps = max_total_fee_bps;
+        dynamic_fee.volatility_accumulator = dynamic_fee
+            .volatility_accumulator
+            .min(max_volatility_accumulator);
+        dynamic_fee.volatility_reference = dynamic_fee
+            .volatility_reference
+            .min(max_volatility_accumulator);
+
+        emit_cpi!(DynamicFeeParamsUpdated {
+            pool: pool.key(),
+            filter_period,
+            decay_period,
+            reduction_factor,
+            variable_fee_control,
+            max_volatility_accumulator,
+            max_total_fee_bps,
+        });
+        Ok(())
+    }
+
+    /// Points the pool's deposit valuation at oracle price accounts, or back
+    /// to its static prices with `ORACLE_KIND_NONE`.
+    pub fn set_pool_oracles(
+        ctx: Context<SetPoolOracles>,
+        oracle_kind: u8,
+        oracle_a: Pubkey,
+        oracle_b: Pubkey,
+        max_oracle_age_secs: u32,
+        max_oracle_confidence_bps: u16,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+        match oracle_kind {
+            ORACLE_KIND_NONE => {}
+            ORACLE_KIND_PYTH_STYLE => {
+                if oracle_a == Pubkey::default()
+                    || oracle_b == Pubkey::default()
+                    || max_oracle_confidence_bps as u64 > BPS_DENOMINATOR
+                {
+                    return err!(DexError::InvalidOracle);
+                }
+            }
+            _ => return err!(DexError::InvalidOracle),
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.oracle_kind = oracle_kind;
+        pool.oracle_a = oracle_a;
+        pool.oracle_b = oracle_b;
+        pool.max_oracle_age_secs = max_oracle_age_secs;
+        pool.max_oracle_confidence_bps = max_oracle_confidence_bps;
+
+        emit_cpi!(PoolOraclesUpdated {
+            pool: pool.key(),
+            oracle_kind,
+            oracle_a,
+            oracle_b,
+            max_oracle_age_secs,
+            max_oracle_confidence_bps,
+        });
+        Ok(())
+    }
+
+    /// Corrects the pool's static prices. Each price may move at most
+    /// `MAX_REFERENCE_PRICE_DEVIATION_BPS` per update, and updates must be
+    /// `MIN_REFERENCE_PRICE_UPDATE_SLOTS` apart. Bin spacing is unchanged.
+    pub fn update_reference_prices(
+        ctx: Context<UpdateReferencePrices>,
+        token_a_price: u128,
+        token_b_price: u128,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let slot = Clock::get()?.slot;
+        if pool.last_price_update_slot != 0
+            && slot < pool.last_price_update_slot + MIN_REFERENCE_PRICE_UPDATE_SLOTS
+        {
+            return err!(DexError::PriceUpdateTooFrequent);
+        }
+        check_price_deviation(pool.token_a_price, token_a_price)?;
+        check_price_deviation(pool.token_b_price, token_b_price)?;
+
+        let old_token_a_price = pool.token_a_price;
+        let old_token_b_price = pool.token_b_price;
+        pool.token_a_price = token_a_price;
+        pool.token_b_price = token_b_price;
+        pool.last_price_update_slot = slot;
+
+        emit_cpi!(ReferencePricesUpdated {
+            pool: pool.key(),
+            old_token_a_price,
+            old_token_b_price,
+            token_a_price,
+            token_b_price,
+            slot,
+        });
+        Ok(())
+    }
+
+    /// Sets how far one-sided deposits may deepen each side of the active bin
+    /// relative to the other. Zero disables one-sided deposits on that side.
+    pub fn set_depth_ratios(
+        ctx: Context<SetDepthRatios>,
+        depth_ratio_above_bps: u32,
+        depth_ratio_below_bps: u32,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+        if depth_ratio_above_bps > MAX_DEPTH_RATIO_BPS
+            || depth_ratio_below_bps > MAX_DEPTH_RATIO_BPS
+        {
+            return err!(DexError::InvalidDepthRatio);
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        let old_depth_ratio_above_bps = pool.depth_ratio_above_bps;
+        let old_depth_ratio_below_bps = pool.depth_ratio_below_bps;
+        pool.depth_ratio_above_bps = depth_ratio_above_bps;
+        pool.depth_ratio_below_bps = depth_ratio_below_bps;
+
+        emit_cpi!(DepthRatiosUpdated {
+            pool: pool.key(),
+            old_depth_ratio_above_bps,
+            old_depth_ratio_below_bps,
+            depth_ratio_above_bps,
+            depth_ratio_below_bps,
+        });
+        Ok(())
+    }
+
+    pub fn set_flash_loan_fee(
+        ctx: Context<SetFlashLoanFee>,
+        flash_loan_fee_bps: u16,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+        if flash_loan_fee_bps > MAX_FLASH_LOAN_FEE_BPS {
+            return err!(DexError::InvalidFlashLoanFee);
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        let old_fee_bps = pool.flash_loan_fee_bps;
+        pool.flash_loan_fee_bps = flash_loan_fee_bps;
+
+        emit_cpi!(FlashLoanFeeUpdated {
+            pool: pool.key(),
+            old_fee_bps,
+            new_fee_bps: flash_loan_fee_bps,
+        });
+        Ok(())
+    }
+
+    /// Lends `amount` of one pool token out of its vault. A
+    /// `flash_loan_repay` for the same pool must follow later in the same
+    /// transaction, checked through the instructions sysvar.
+    pub fn flash_loan_borrow(
+        ctx: Context<FlashLoanBorrow>,
+        amount: u64,
+        borrow_a: bool,
+    ) -> Result<()> {
+        if amount == 0 {
+            return err!(DexError::InvalidAmount);
+        }
+        let pool = &mut ctx.accounts.pool;
+        check_no_flash_loan(pool)?;
+        check_flash_loan_repaid(&ctx.accounts.instructions, &pool.key())?;
+
+        // The repay credits the fee to the LPs of the lent token in the
+        // active bin array, which cannot change until then; a loan whose fee
+        // nobody would receive could never be repaid.
+        let fee = fee_on_amount(amount, pool.flash_loan_fee_bps)?;
+        if fee != 0 && ctx.accounts.bin_array.load()?.lp_holdings(borrow_a) == 0 {
+            return err!(DexError::NoLiquidityForFee);
+        }
+
+        pool.flash_loan_amount = amount;
+        pool.flash_loan_fee = fee;
+        pool.flash_loan_in_a = borrow_a;
+
+        transfer_from_vault(
+            &ctx.accounts.token_program,
+            pool,
+            &ctx.accounts.vault,
+            &ctx.accounts.borrower_token,
+            amount,
+        )?;
+
+        emit_cpi!(FlashLoanBorrowed {
+            pool: pool.key(),
+            borrower: ctx.accounts.borrower.key(),
+            amount,
+            fee: pool.flash_loan_fee,
+            in_a: borrow_a,
+        });
+        Ok(())
+    }
+
+    /// Repays the pool's outstanding flash loan plus its fee. The fee is
+    /// credited through fee growth to the LPs of the active bin array, in
+    /// proportion to how much of the lent token each bin holds.
+    pub fn flash_loan_repay(ctx: Context<FlashLoanRepay>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        if pool.flash_loan_amount == 0 {
+            return err!(DexError::NoFlashLoan);
+        }
+        let (amount, fee, in_a) = (
+            pool.flash_loan_amount,
+            pool.flash_loan_fee,
+            pool.flash_loan_in_a,
+        );
+
+        transfer_to_vault(
+            &ctx.accounts.token_program,
+            &ctx.accounts.borrower,
+            &ctx.accounts.borrower_token,
+            &ctx.accounts.vault,
+            amount.checked_add(fee).ok_or(DexError::Overflow)?,
+        )?;
+
+        ctx.accounts
+            .bin_array
+            .load_mut()?
+            .accrue_fee_by_holdings(fee, in_a)?;
+
+        pool.flash_loan_amount = 0;
+        pool.flash_loan_fee = 0;
+
+        emit_cpi!(FlashLoanRepaid {
+            pool: pool.key(),
+            borrower: ctx.accounts.borrower.key(),
+            amount,
+            fee,
+            in_a,
+        });
+        Ok(())
+    }
+
+    /// Rewrites a `Pool` or `Position` created before prices were Q64.64,
+    /// widening its two u64 cent prices in place. Pools also need their two
+    /// mints to record decimals. The admin pays the rent for the extra bytes.
+    pub fn migrate_legacy_prices(ctx: Context<MigrateLegacyPrices>) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.config.admin,
+            ctx.accounts.admin.key(),
+            DexError::Unauthorized
+        );
+
+        let account = ctx.accounts.account.to_account_info();
+        require_keys_eq!(*account.owner, crate::ID, DexError::InvalidMigration);
+        let mints = match (&ctx.accounts.token_a_mint, &ctx.accounts.token_b_mint) {
+            (Some(token_a_mint), Some(token_b_mint)) => Some([
+                (token_a_mint.key(), token_a_mint.decimals),
+                (token_b_mint.key(), token_b_mint.decimals),
+            ]),
+            _ => None,
+        };
+        let migrated = widen_legacy_prices(&account.try_borrow_data()?, mints)?;
+
+        let rent = Rent::get()?.minimum_balance(migrated.len());
+        let top_up = rent.saturating_sub(account.lamports());
+        if top_up > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.admin.to_account_info(),
+                        to: account.clone(),
+                    },
+                ),
+                top_up,
+            )?;
+        }
+        account.realloc(migrated.len(), false)?;
+        account.try_borrow_mut_data()?.copy_from_slice(&migrated);
+
+        emit_cpi!(LegacyPricesMigrated {
+            account: account.key(),
+        });
+        Ok(())
+    }
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct StakeLpNft<'info> {
-    pub pool: Account<'info, Pool>,
-    pub position: Account<'info, Position>,
-
+pub struct Initialize<'info> {
     #[account(
-        init_if_needed,
-        payer = owner,
-        space = 8 + Stake::SIZE,
-        seeds = [b"stake", position.key().as_ref()],
+        init,
+        payer = admin,
+        space = 8 + Config::SIZE,
+        seeds = [b"config"],
         bump
     )]
-    pub stake: Account<'info, Stake>,
-
-    #[account(
-        init_if_needed,
-        payer = owner,
-        associated_token::mint = lp_mint,
-        associated_token::authority = stake
-    )]
-    pub stake_vault: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub owner_lp_token_account: Account<'info, TokenAccount>,
-
-    pub lp_mint: Account<'info, Mint>,
+    pub config: Account<'info, Config>,
 
     #[account(mut)]
-    pub owner: Signer<'info>,
+    pub admin: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct UnstakeLpNft<'info> {
-    pub pool: Account<'info, Pool>,
-    pub position: Account<'info, Position>,
-
+pub struct SetAdmin<'info> {
     #[account(
         mut,
-        seeds = [b"stake", position.key().as_ref()],
-        bump = stake.bump,
-        close = owner
+        seeds = [b"config"],
+        bump = config.bump,
     )]
-    pub stake: Account<'info, Stake>,
+    pub config: Account<'info, Config>,
 
+    pub admin: Signer<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SetTreasury<'info> {
     #[account(
         mut,
-        associated_token::mint = lp_mint,
-        associated_token::authority = stake
+        seeds = [b"config"],
+        bump = config.bump,
     )]
-    pub stake_vault: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub owner_lp_token_account: Account<'info, TokenAccount>,
-
-    pub lp_mint: Account<'info, Mint>,
-
-    #[account(mut)]
-    pub owner: Signer<'info>,
+    pub config: Account<'info, Config>,
 
-    pub token_program: Program<'info, Token>,
+    pub admin: Signer<'info>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct AddLiquidityToPosition<'info> {
-    #[account(mut)]
-    pub pool: Account<'info, Pool>,
+pub struct SetProtocolFeeShare<'info> {
     #[account(
         mut,
-        seeds = [b"position", pool.key().as_ref(), &position.position_id.to_le_bytes()],
-        bump = position.bump
+        seeds = [b"config"],
+        bump = config.bump,
     )]
-    pub position: Account<'info, Position>,
+    pub config: Account<'info, Config>,
 
+    pub admin: Signer<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SetPoolProtocolFeeShare<'info> {
     #[account(
-        mut,
-        seeds = [b"lp_mint", position.key().as_ref()],
-        bump
+        seeds = [b"config"],
+        bump = config.bump,
     )]
-    pub lp_mint: Account<'info, Mint>,
+    pub config: Account<'info, Config>,
 
     #[account(
         mut,
-        associated_token::mint = lp_mint,
-        associated_token::authority = owner
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
     )]
-    pub owner_lp_token_account: Account<'info, TokenAccount>,
+    pub pool: Account<'info, Pool>,
 
-    #[account(mut)]
-    pub owner_token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub owner_token_b: Account<'info, TokenAccount>,
+    pub admin: Signer<'info>,
+}
 
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SetDynamicFeeParams<'info> {
     #[account(
-        mut,
-        associated_token::mint = token_a_mint,
-        associated_token::authority = pool
+        seeds = [b"config"],
+        bump = config.bump,
     )]
-    pub vault_a: Account<'info, TokenAccount>,
+    pub config: Account<'info, Config>,
+
     #[account(
         mut,
-        associated_token::mint = token_b_mint,
-        associated_token::authority = pool
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
     )]
-    pub vault_b: Account<'info, TokenAccount>,
+    pub pool: Account<'info, Pool>,
 
-    pub token_a_mint: Account<'info, Mint>,
-    pub token_b_mint: Account<'info, Mint>,
+    pub admin: Signer<'info>,
+}
 
-    #[account(mut)]
-    pub owner: Signer<'info>,
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SetPoolOracles<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub admin: Signer<'info>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct ClosePosition<'info> {
+pub struct UpdateReferencePrices<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
     pub pool: Account<'info, Pool>,
 
+    pub admin: Signer<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SetDepthRatios<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
     #[account(
         mut,
-        seeds = [b"position", pool.key().as_ref(), &position.position_id.to_le_bytes()],
-        bump = position.bump,
-        close = owner
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub admin: Signer<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SetFlashLoanFee<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
     )]
-    pub position: Account<'info, Position>,
+    pub config: Account<'info, Config>,
 
     #[account(
         mut,
-        seeds = [b"lp_mint", position.key().as_ref()],
-        bump
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
     )]
-    pub lp_mint: Account<'info, Mint>,
+    pub pool: Account<'info, Pool>,
+
+    pub admin: Signer<'info>,
+}
 
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(amount: u64, borrow_a: bool)]
+pub struct FlashLoanBorrow<'info> {
     #[account(
         mut,
-        associated_token::mint = lp_mint,
-        associated_token::authority = owner
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
     )]
-    pub owner_lp_token_account: Account<'info, TokenAccount>,
+    pub pool: Account<'info, Pool>,
 
     #[account(
         mut,
-        seeds = [b"stake", position.key().as_ref()],
-        bump = stake.bump
+        address = if borrow_a { pool.vault_a } else { pool.vault_b } @ DexError::InvalidVault
     )]
-    pub stake: Account<'info, Stake>,
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = vault.mint)]
+    pub borrower_token: Account<'info, TokenAccount>,
 
-    #[account(mut)]
-    pub owner: Signer<'info>,
+    /// The bin array holding the active bin, whose LPs will earn the fee.
+    #[account(
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(pool.active_bin_id).to_le_bytes()],
+        bump = bin_array.load()?.bump,
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    pub borrower: Signer<'info>,
+
+    /// CHECK: address is the instructions sysvar.
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
 
     pub token_program: Program<'info, Token>,
 }
-#[account]
-pub struct Config {
-    pub admin: Pubkey,
-    pub bump: u8,
-    pub initialized: bool,
-}
 
-impl Config {
-    pub const SIZE: usize = 32 + 1 + 1;
-}
+#[event_cpi]
+#[derive(Accounts)]
+pub struct FlashLoanRepay<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
 
-#[account]
-pub struct Registry {
-    pub admin: Pubkey,
-    pub bump: u8,
-    pub next_pool_id: u64,
-    pub initialized: bool,
-}
+    #[account(
+        mut,
+        address = if pool.flash_loan_in_a { pool.vault_a } else { pool.vault_b } @ DexError::InvalidVault
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = vault.mint, token::authority = borrower)]
+    pub borrower_token: Account<'info, TokenAccount>,
 
-impl Registry {
-    pub const SIZE: usize = 32 + 1 + 8 + 1;
-}
+    /// The bin array holding the active bin; its LPs earn the fee.
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(pool.active_bin_id).to_le_bytes()],
+        bump = bin_array.load()?.bump,
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
 
-#[account]
-pub struct Pool {
-    pub pool_id: u64,
-    pub creator: Pubkey,
-    pub token_a_mint: Pubkey,
-    pub token_b_mint: Pubkey,
-    pub token_a_kind: u8,
-    pub token_b_kind: u8,
-    pub token_a_frozen: bool,
-    pub token_b_frozen: bool,
-    pub fee_bps: u16,
-    pub lp_fee_bps: u16,
-    pub house_fee_bps: u16,
-    pub bin_spacing_milli_cents: u64,
-    pub guarantee_policy: u8,
-    pub allowed_assets_mask: u16,
-    pub guarantee_mint: Pubkey,
-    pub token_a_price_cents: u64,
-    pub token_b_price_cents: u64,
-    pub total_a_amount: u64,
-    pub total_b_amount: u64,
-    pub vault_a: Pubkey,
-    pub vault_b: Pubkey,
-    pub next_position_id: u64,
-    pub bump: u8,
-}
+    pub borrower: Signer<'info>,
 
-impl Pool {
-    pub const SIZE: usize = 8
-        + 32
-        + 32
-        + 32
-        + 1
-        + 1
-        + 1
-        + 1
-        + 2
-        + 2
-        + 2
-        + 8
-        + 1
-        + 2
-        + 32
-        + 8
-        + 8
-        + 8
-        + 8
-        + 32
-        + 32
-        + 8
-        + 1;
+    pub token_program: Program<'info, Token>,
 }
 
-#[account]
-pub struct Position {
-    pub pool: Pubkey,
-    pub owner: Pubkey,
-    pub position_id: u64,
-    pub lp_mint: Pubkey,
-    pub min_price_cents: u64,
-    pub max_price_cents: u64,
-    pub left_function_type: u8,
-    pub right_function_type: u8,
-    pub left_params: [i64; 5],
-    pub right_params: [i64; 5],
-    pub amount_a: u64,
-    pub amount_b: u64,
-    pub bump: u8,
-}
+#[event_cpi]
+#[derive(Accounts)]
+pub struct MigrateLegacyPrices<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
 
-impl Position {
-    pub const SIZE: usize =
-        32 + 32 + 8 + 32 + 8 + 8 + 1 + 1 + (8 * 5) + (8 * 5) + 8 + 8 + 1;
-}
+    /// CHECK: owner, discriminator and legacy length are checked in the handler.
+    #[account(mut)]
+    pub account: UncheckedAccount<'info>,
 
-#[account]
-pub struct Stake {
-    pub pool: Pubkey,
-    pub position: Pubkey,
-    pub owner: Pubkey,
-    pub staked_at_slot: u64,
-    pub rebate_bps: u16,
-    pub active: bool,
-    pub bump: u8,
-}
+    /// Required for pools; checked against the pool's mints in the handler.
+    pub token_a_mint: Option<Account<'info, Mint>>,
+    pub token_b_mint: Option<Account<'info, Mint>>,
 
-impl Stake {
-    pub const SIZE: usize = 32 + 32 + 32 + 8 + 2 + 1 + 1;
-}
+    #[account(mut)]
+    pub admin: Signer<'info>,
 
-#[error_code]
-pub enum DexError {
-    #[msg("Config already initialized")]
-    AlreadyInitialized,
-    #[msg("Missing PDA bump")]
-    MissingBump,
-    #[msg("Unauthorized")]
-    Unauthorized,
-    #[msg("Invalid fee parameters")]
-    InvalidFee,
-    #[msg("Token mint must be frozen")]
-    MintNotFrozen,
-    #[msg("Token mint must not be frozen")]
-    MintMustBeUnfrozen,
-    #[msg("Invalid token kind")]
-    InvalidTokenKind,
-    #[msg("Invalid guarantee policy")]
-    InvalidGuaranteePolicy,
-    #[msg("Already staked")]
-    AlreadyStaked,
-    #[msg("Not staked")]
-    NotStaked,
-    #[msg("Invalid position")]
-    InvalidPosition,
-    #[msg("Arithmetic overflow")]
-    Overflow,
-    #[msg("Invalid price inputs")]
-    InvalidPrice,
-    #[msg("Position already initialized")]
-    PositionAlreadyInitialized,
-    #[msg("Invalid price range")]
-    InvalidPriceRange,
-    #[msg("Invalid function spec")]
-    InvalidFunctionSpec,
-    #[msg("Invalid amount")]
-    InvalidAmount,
-    #[msg("One-sided deposit not allowed by depth rule")]
-    OneSidedNotAllowed,
+    pub system_program: Program<'info, System>,
 }
 
-fn compute_bin_spacing_milli_cents(
-    token_a_price_cents: u64,
-    token_b_price_cents: u64,
-) -> Result<u64> {
-    if token_a_price_cents == 0 || token_b_price_cents == 0 {
-        return err!(DexError::InvalidPrice);
-    }
-    // Bin spacing is the average price (in cents) expressed in milli-cents.
-    // Examples:
-    // - $1.00 (100 cents) => 1000 milli-cents (1 cent)
-    // - $0.50 (50 cents) => 500 milli-cents (0.5 cents)
-    // - $10.00 (1000 cents) => 10000 milli-cents (10 cents)
-    let sum = token_a_price_cents
-        .checked_add(token_b_price_cents)
-        .ok_or(DexError::Overflow)?;
-    let avg_cents = sum / 2;
-    avg_cents
-        .checked_mul(10)
-        .ok_or(DexError::Overflow)
+#[event_cpi]
+#[derive(Accounts)]
+pub struct InitRegistry<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Registry::SIZE,
+        seeds = [b"registry"],
+        bump
+    )]
+    pub registry: Account<'info, Registry>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
 }
 
-fn validate_token_kind(kind: u8, mint: &Account<Mint>, label: &str) -> Result<()> {
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CreatePool<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"registry"],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, Registry>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Pool::SIZE,
+        seeds = [b"pool", &registry.next_pool_id.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub token_a_mint: Account<'info, Mint>,
+    pub token_b_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = admin,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(index: i64)]
+pub struct InitializeBinArray<'info> {
+    #[account(
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + BinArray::SIZE,
+        seeds = [b"bin_array", pool.key().as_ref(), &index.to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct InitializeObservations<'info> {
+    #[account(
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Observations::space(1),
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: Account<'info, Observations>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(capacity: u16)]
+pub struct GrowObservations<'info> {
+    #[account(
+        mut,
+        seeds = [b"observations", observations.pool.as_ref()],
+        bump = observations.bump,
+        realloc = 8 + Observations::space(capacity as usize),
+        realloc::payer = payer,
+        realloc::zero = false,
+    )]
+    pub observations: Account<'info, Observations>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Twap<'info> {
+    #[account(
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"observations", pool.key().as_ref()],
+        bump = observations.bump,
+    )]
+    pub observations: Account<'info, Observations>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CreateLpPosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Position::SIZE,
+        seeds = [b"position", pool.key().as_ref(), &pool.next_position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        init,
+        payer = owner,
+        mint::decimals = 0,
+        mint::authority = position,
+        seeds = [b"lp_mint", position.key().as_ref()],
+        bump
+    )]
+    pub lp_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        associated_token::mint = lp_mint,
+        associated_token::authority = owner
+    )]
+    pub owner_lp_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner_token_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ DexError::InvalidVault,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        address = pool.vault_b @ DexError::InvalidVault,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_lower.load()?.index.to_le_bytes()],
+        bump = bin_array_lower.load()?.bump,
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_upper.load()?.index.to_le_bytes()],
+        bump = bin_array_upper.load()?.bump,
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    /// CHECK: required when the pool has oracles; matched against
+    /// `pool.oracle_a` and parsed by the oracle reader.
+    pub oracle_a: Option<UncheckedAccount<'info>>,
+    /// CHECK: as `oracle_a`, for token B.
+    pub oracle_b: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct StakeLpNft<'info> {
+    pub pool: Account<'info, Pool>,
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + Stake::SIZE,
+        seeds = [b"stake", position.key().as_ref()],
+        bump
+    )]
+    pub stake: Account<'info, Stake>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        associated_token::mint = lp_mint,
+        associated_token::authority = stake
+    )]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner_lp_token_account: Account<'info, TokenAccount>,
+
+    pub lp_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct UnstakeLpNft<'info> {
+    pub pool: Account<'info, Pool>,
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"stake", position.key().as_ref()],
+        bump = stake.bump,
+        close = owner
+    )]
+    pub stake: Account<'info, Stake>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = stake
+    )]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner_lp_token_account: Account<'info, TokenAccount>,
+
+    pub lp_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct AddLiquidityToPosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        mut,
+        seeds = [b"position", pool.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_mint", position.key().as_ref()],
+        bump
+    )]
+    pub lp_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = owner
+    )]
+    pub owner_lp_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner_token_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ DexError::InvalidVault,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        address = pool.vault_b @ DexError::InvalidVault,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_lower.load()?.index.to_le_bytes()],
+        bump = bin_array_lower.load()?.bump,
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_upper.load()?.index.to_le_bytes()],
+        bump = bin_array_upper.load()?.bump,
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    /// CHECK: required when the pool has oracles; matched against
+    /// `pool.oracle_a` and parsed by the oracle reader.
+    pub oracle_a: Option<UncheckedAccount<'info>>,
+    /// CHECK: as `oracle_a`, for token B.
+    pub oracle_b: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        mut,
+        seeds = [b"position", pool.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        token::mint = position.lp_mint,
+        token::authority = owner
+    )]
+    pub owner_lp_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: the position's stake PDA, empty until the position is first
+    /// staked; read by `check_not_staked`.
+    #[account(
+        seeds = [b"stake", position.key().as_ref()],
+        bump
+    )]
+    pub stake: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        token::mint = token_a_mint,
+        token::authority = owner
+    )]
+    pub owner_token_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::mint = token_b_mint,
+        token::authority = owner
+    )]
+    pub owner_token_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_lower.load()?.index.to_le_bytes()],
+        bump = bin_array_lower.load()?.bump,
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_upper.load()?.index.to_le_bytes()],
+        bump = bin_array_upper.load()?.bump,
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ClaimFees<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(
+        mut,
+        seeds = [b"position", pool.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        token::mint = position.lp_mint,
+        token::authority = holder
+    )]
+    pub holder_lp_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = token_a_mint
+    )]
+    pub holder_token_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::mint = token_b_mint
+    )]
+    pub holder_token_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_lower.load()?.index.to_le_bytes()],
+        bump = bin_array_lower.load()?.bump,
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+    #[account(
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_upper.load()?.index.to_le_bytes()],
+        bump = bin_array_upper.load()?.bump,
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    pub holder: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"position", pool.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump,
+        close = owner
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_mint", position.key().as_ref()],
+        bump
+    )]
+    pub lp_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = owner
+    )]
+    pub owner_lp_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: the position's stake PDA, empty until the position is first
+    /// staked; read by `check_not_staked`.
+    #[account(
+        seeds = [b"stake", position.key().as_ref()],
+        bump
+    )]
+    pub stake: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        token::mint = token_a_mint,
+        token::authority = owner
+    )]
+    pub owner_token_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::mint = token_b_mint,
+        token::authority = owner
+    )]
+    pub owner_token_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_lower.load()?.index.to_le_bytes()],
+        bump = bin_array_lower.load()?.bump,
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_upper.load()?.index.to_le_bytes()],
+        bump = bin_array_upper.load()?.bump,
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+#[event_cpi]
+#[derive(Accounts)]
+pub struct PlaceLimitOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + LimitOrder::SIZE,
+        seeds = [b"limit_order", pool.key().as_ref(), &pool.next_order_id.to_le_bytes()],
+        bump
+    )]
+    pub order: Account<'info, LimitOrder>,
+
+    #[account(mut, constraint = bin_array.load()?.pool == pool.key() @ DexError::InvalidBinArray)]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = vault.mint, token::authority = owner)]
+    pub owner_token: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ClaimFilledOrder<'info> {
+    #[account(
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"limit_order", pool.key().as_ref(), &order.order_id.to_le_bytes()],
+        bump = order.bump
+    )]
+    pub order: Account<'info, LimitOrder>,
+
+    #[account(
+        mut,
+        constraint = bin_array.load()?.pool == pool.key() @ DexError::InvalidBinArray
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = vault.mint)]
+    pub owner_token: Account<'info, TokenAccount>,
+
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CancelLimitOrder<'info> {
+    #[account(
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"limit_order", pool.key().as_ref(), &order.order_id.to_le_bytes()],
+        bump = order.bump
+    )]
+    pub order: Account<'info, LimitOrder>,
+
+    #[account(mut, constraint = bin_array.load()?.pool == pool.key() @ DexError::InvalidBinArray)]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub vault_in: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = vault_in.mint)]
+    pub owner_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub vault_out: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = vault_out.mint)]
+    pub owner_token_out: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump = observations.bump,
+    )]
+    pub observations: Account<'info, Observations>,
+
+    #[account(
+        mut,
+        token::mint = token_a_mint,
+        token::authority = user
+    )]
+    pub user_token_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::mint = token_b_mint,
+        token::authority = user
+    )]
+    pub user_token_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SwapRoute<'info> {
+    #[account(mut, token::authority = user)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    #[account(mut, token::authority = user)]
+    pub user_token_out: Account<'info, TokenAccount>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct WithdrawHouseFees<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", &pool.pool_id.to_le_bytes()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = pool
+    )]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = token_a_mint,
+        token::authority = config.treasury
+    )]
+    pub treasury_token_a: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::mint = token_b_mint,
+        token::authority = config.treasury
+    )]
+    pub treasury_token_b: Account<'info, TokenAccount>,
+
+    #[account(address = pool.token_a_mint)]
+    pub token_a_mint: Account<'info, Mint>,
+    #[account(address = pool.token_b_mint)]
+    pub token_b_mint: Account<'info, Mint>,
+
+    pub admin: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[account]
+pub struct Config {
+    pub admin: Pubkey,
+    pub bump: u8,
+    pub initialized: bool,
+    pub treasury: Pubkey,
+    pub protocol_fee_share_bps: u16,
+}
+
+impl Config {
+    pub const SIZE: usize = 32 + 1 + 1 + 32 + 2;
+}
+
+#[account]
+pub struct Registry {
+    pub admin: Pubkey,
+    pub bump: u8,
+    pub next_pool_id: u64,
+    pub initialized: bool,
+}
+
+impl Registry {
+    pub const SIZE: usize = 32 + 1 + 8 + 1;
+}
+
+#[account]
+pub struct Pool {
+    pub pool_id: u64,
+    pub creator: Pubkey,
+    pub token_a_mint: Pubkey,
+    pub token_b_mint: Pubkey,
+    pub token_a_kind: u8,
+    pub token_b_kind: u8,
+    pub token_a_frozen: bool,
+    pub token_b_frozen: bool,
+    pub fee_bps: u16,
+    pub lp_fee_bps: u16,
+    pub house_fee_bps: u16,
+    pub bin_step_bps: u16,
+    pub guarantee_policy: u8,
+    pub allowed_assets_mask: u16,
+    pub guarantee_mint: Pubkey,
+    /// USD prices of one token, Q64.64.
+    pub token_a_price: u128,
+    pub token_b_price: u128,
+    pub total_a_amount: u64,
+    pub total_b_amount: u64,
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+    pub next_position_id: u64,
+    pub bump: u8,
+    pub active_bin_id: i32,
+    pub house_fees_a: u64,
+    pub house_fees_b: u64,
+    pub protocol_fee_share_bps: u16,
+    pub dynamic_fee: DynamicFee,
+    pub oracle_kind: u8,
+    pub oracle_a: Pubkey,
+    pub oracle_b: Pubkey,
+    pub max_oracle_age_secs: u32,
+    pub max_oracle_confidence_bps: u16,
+    pub last_price_update_slot: u64,
+    pub token_a_decimals: u8,
+    pub token_b_decimals: u8,
+    /// Token A sits above the active bin and token B below it.
+    pub depth_ratio_above_bps: u32,
+    pub depth_ratio_below_bps: u32,
+    pub flash_loan_fee_bps: u16,
+    /// Outstanding flash loan within the current transaction, if any.
+    pub flash_loan_amount: u64,
+    pub flash_loan_fee: u64,
+    pub flash_loan_in_a: bool,
+    pub next_order_id: u64,
+}
+
+impl Pool {
+    pub const SIZE: usize = 8
+        + 32
+        + 32
+        + 32
+        + 1
+        + 1
+        + 1
+        + 1
+        + 2
+        + 2
+        + 2
+        + 2
+        + 1
+        + 2
+        + 32
+        + 16
+        + 16
+        + 8
+        + 8
+        + 32
+        + 32
+        + 8
+        + 1
+        + 4
+        + 8
+        + 8
+        + 2
+        + DynamicFee::SIZE
+        + 1
+        + 32
+        + 32
+        + 4
+        + 2
+        + 8
+        + 1
+        + 1
+        + 4
+        + 4
+        + 2
+        + 8
+        + 8
+        + 1
+        + 8;
+}
+
+/// Volatility-driven fee state. Periods are in slots; `reduction_factor` is
+/// in bps of the accumulator carried over as the next reference.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct DynamicFee {
+    pub filter_period: u16,
+    pub decay_period: u16,
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub max_volatility_accumulator: u32,
+    pub max_total_fee_bps: u16,
+    pub volatility_accumulator: u32,
+    pub volatility_reference: u32,
+    pub index_reference: i32,
+    pub last_update_slot: u64,
+}
+
+impl DynamicFee {
+    pub const SIZE: usize = 2 + 2 + 2 + 4 + 4 + 2 + 4 + 4 + 4 + 8;
+
+    /// Called once per swap. Swaps closer together than `filter_period` keep
+    /// the same references; after `decay_period` the volatility resets.
+    pub fn update_references(&mut self, active_bin_id: i32, slot: u64) {
+        let elapsed = slot.saturating_sub(self.last_update_slot);
+        if elapsed >= self.filter_period as u64 {
+            self.index_reference = active_bin_id;
+            self.volatility_reference = if elapsed < self.decay_period as u64 {
+                (self.volatility_accumulator as u64 * self.reduction_factor as u64
+                    / BPS_DENOMINATOR) as u32
+            } else {
+                0
+            };
+        }
+        self.last_update_slot = slot;
+    }
+
+    /// Called for every bin the swap reaches, before its fee is charged.
+    pub fn update_volatility_accumulator(&mut self, bin_id: i32) {
+        let distance = (self.index_reference as i64 - bin_id as i64).unsigned_abs();
+        let accumulator = (self.volatility_reference as u64)
+            .saturating_add(distance.saturating_mul(VOLATILITY_SCALE as u64));
+        self.volatility_accumulator =
+            accumulator.min(self.max_volatility_accumulator as u64) as u32;
+    }
+
+    /// Variable fee in bps, rounded up.
+    pub fn variable_fee_bps(&self) -> u64 {
+        let accumulator = self.volatility_accumulator as u128;
+        let fee = (self.variable_fee_control as u128 * accumulator * accumulator)
+            .div_ceil(VARIABLE_FEE_PRECISION);
+        u64::try_from(fee).unwrap_or(u64::MAX)
+    }
+
+    /// Base plus variable fee, capped at `max_total_fee_bps`.
+    pub fn total_fee_bps(&self, base_fee_bps: u16) -> u16 {
+        let total = (base_fee_bps as u64).saturating_add(self.variable_fee_bps());
+        total.min(self.max_total_fee_bps.max(base_fee_bps) as u64) as u16
+    }
+}
+
+#[account]
+pub struct Position {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub lp_mint: Pubkey,
+    /// USD price range of token A, Q64.64.
+    pub min_price: u128,
+    pub max_price: u128,
+    pub left_function_type: u8,
+    pub right_function_type: u8,
+    pub left_params: [i64; 5],
+    pub right_params: [i64; 5],
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub bump: u8,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+    pub liquidity_shares: [u128; MAX_BINS_PER_DEPOSIT],
+    pub fee_growth_checkpoints_a: [u128; MAX_BINS_PER_DEPOSIT],
+    pub fee_growth_checkpoints_b: [u128; MAX_BINS_PER_DEPOSIT],
+    pub fees_owed_a: u64,
+    pub fees_owed_b: u64,
+}
+
+impl Position {
+    pub const SIZE: usize = 32
+        + 32
+        + 8
+        + 32
+        + 16
+        + 16
+        + 1
+        + 1
+        + (8 * 5)
+        + (8 * 5)
+        + 8
+        + 8
+        + 1
+        + 4
+        + 4
+        + (16 * MAX_BINS_PER_DEPOSIT)
+        + (16 * MAX_BINS_PER_DEPOSIT)
+        + (16 * MAX_BINS_PER_DEPOSIT)
+        + 8
+        + 8;
+
+    pub fn share_index(&self, bin_id: i32) -> Result<usize> {
+        if bin_id < self.lower_bin_id || bin_id > self.upper_bin_id {
+            return err!(DexError::InvalidBinId);
+        }
+        Ok((bin_id - self.lower_bin_id) as usize)
+    }
+
+    /// Moves fees earned by this position's shares in `bin` since the last
+    /// checkpoint into `fees_owed_*`. Must run before the shares change.
+    pub fn accrue_fees(&mut self, bin_id: i32, bin: &Bin) -> Result<()> {
+        let index = self.share_index(bin_id)?;
+        let shares = self.liquidity_shares[index];
+        let owed_a = pending_fee(
+            shares,
+            bin.fee_growth_a,
+            self.fee_growth_checkpoints_a[index],
+        )?;
+        let owed_b = pending_fee(
+            shares,
+            bin.fee_growth_b,
+            self.fee_growth_checkpoints_b[index],
+        )?;
+        self.fees_owed_a = self
+            .fees_owed_a
+            .checked_add(owed_a)
+            .ok_or(DexError::Overflow)?;
+        self.fees_owed_b = self
+            .fees_owed_b
+            .checked_add(owed_b)
+            .ok_or(DexError::Overflow)?;
+        self.fee_growth_checkpoints_a[index] = bin.fee_growth_a;
+        self.fee_growth_checkpoints_b[index] = bin.fee_growth_b;
+        Ok(())
+    }
+}
+
+#[zero_copy]
+#[derive(Default)]
+pub struct Bin {
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub liquidity_supply: u128,
+    /// Cumulative LP fees per share, Q64.64, wrapping.
+    pub fee_growth_a: u128,
+    pub fee_growth_b: u128,
+    /// Unfilled limit orders selling token A (or B) in this bin, their share
+    /// supply, and a round bumped each time that side fills completely.
+    pub order_amount_a: u64,
+    pub order_amount_b: u64,
+    pub order_supply_a: u128,
+    pub order_supply_b: u128,
+    pub order_round_a: u32,
+    pub order_round_b: u32,
+    /// What swaps paid for fills of the orders selling token A (or B) and
+    /// the orders have not claimed yet.
+    pub order_proceeds_a: u64,
+    pub order_proceeds_b: u64,
+    /// Keeps the size a multiple of the u128 alignment.
+    pub _padding: [u8; 8],
+}
+
+impl Bin {
+    pub const SIZE: usize = 8 + 8 + 16 + 16 + 16 + 8 + 8 + 16 + 16 + 4 + 4 + 8 + 8 + 8;
+
+    /// Credits `fee` of token A (or B) to every share in the bin.
+    pub fn accrue_fee(&mut self, fee: u64, fee_in_a: bool) {
+        if fee == 0 || self.liquidity_supply == 0 {
+            return;
+        }
+        let growth = fee_growth_delta(fee, self.liquidity_supply);
+        if fee_in_a {
+            self.fee_growth_a = self.fee_growth_a.wrapping_add(growth);
+        } else {
+            self.fee_growth_b = self.fee_growth_b.wrapping_add(growth);
+        }
+    }
+
+    /// Unfilled amount, share supply and round of the limit orders selling
+    /// token A (or B).
+    pub fn orders(&self, sell_a: bool) -> (u64, u128, u32) {
+        if sell_a {
+            (self.order_amount_a, self.order_supply_a, self.order_round_a)
+        } else {
+            (self.order_amount_b, self.order_supply_b, self.order_round_b)
+        }
+    }
+
+    fn orders_mut(&mut self, sell_a: bool) -> (&mut u64, &mut u128, &mut u32) {
+        if sell_a {
+            (
+                &mut self.order_amount_a,
+                &mut self.order_supply_a,
+                &mut self.order_round_a,
+            )
+        } else {
+            (
+                &mut self.order_amount_b,
+                &mut self.order_supply_b,
+                &mut self.order_round_b,
+            )
+        }
+    }
+
+    /// Adds up to `amount` to the open orders on one side and returns the
+    /// shares minted, the round they join and the amount credited for them.
+    /// Shares round down, so the credit is what they cost (rounded up) and
+    /// never more than `amount`.
+    pub fn add_order(&mut self, sell_a: bool, amount: u64) -> Result<(u128, u32, u64)> {
+        let (unfilled, supply, round) = self.orders_mut(sell_a);
+        let (shares, credited) = if *supply == 0 {
+            (amount as u128, amount)
+        } else {
+            let shares = (amount as u128)
+                .checked_mul(*supply)
+                .ok_or(DexError::Overflow)?
+                .checked_div(*unfilled as u128)
+                .ok_or(DexError::Overflow)?;
+            let cost = shares
+                .checked_mul(*unfilled as u128)
+                .ok_or(DexError::Overflow)?
+                .div_ceil(*supply);
+            (shares, (cost as u64).min(amount))
+        };
+        if shares == 0 {
+            return err!(DexError::InvalidAmount);
+        }
+        *unfilled = unfilled.checked_add(credited).ok_or(DexError::Overflow)?;
+        *supply = supply.checked_add(shares).ok_or(DexError::Overflow)?;
+        Ok((shares, *round, credited))
+    }
+
+    /// LP amount of token A (or B) in the bin; resting orders hold none.
+    fn lp_holding(&self, in_a: bool) -> u128 {
+        match (self.liquidity_supply, in_a) {
+            (0, _) => 0,
+            (_, true) => self.amount_a as u128,
+            (_, false) => self.amount_b as u128,
+        }
+    }
+
+    fn order_proceeds_mut(&mut self, sell_a: bool) -> &mut u64 {
+        if sell_a {
+            &mut self.order_proceeds_a
+        } else {
+            &mut self.order_proceeds_b
+        }
+    }
+
+    /// Takes `amount` out of the open orders on one side, which swaps paid
+    /// `proceeds` of the other token for. Filling them completely ends the
+    /// round, so later orders start a fresh one.
+    pub fn fill_orders(&mut self, sell_a: bool, amount: u64, proceeds: u64) -> Result<()> {
+        let (unfilled, supply, round) = self.orders_mut(sell_a);
+        *unfilled = unfilled.checked_sub(amount).ok_or(DexError::Overflow)?;
+        if *unfilled == 0 && amount > 0 {
+            *supply = 0;
+            *round = round.wrapping_add(1);
+        }
+        let received = self.order_proceeds_mut(sell_a);
+        *received = received.checked_add(proceeds).ok_or(DexError::Overflow)?;
+        Ok(())
+    }
+}
+
+/// Zero-copy: swaps and deposits read bins in place instead of deserializing
+/// the whole array onto the stack or heap.
+#[account(zero_copy)]
+pub struct BinArray {
+    pub pool: Pubkey,
+    pub index: i64,
+    pub bump: u8,
+    pub _padding: [u8; 7],
+    pub bins: [Bin; BIN_ARRAY_SIZE],
+}
+
+impl BinArray {
+    pub const SIZE: usize = 32 + 8 + 1 + 7 + (Bin::SIZE * BIN_ARRAY_SIZE);
+
+    pub fn contains(&self, bin_id: i32) -> bool {
+        bin_array_index(bin_id) == self.index
+    }
+
+    pub fn bin(&self, bin_id: i32) -> Result<&Bin> {
+        Ok(&self.bins[self.offset(bin_id)?])
+    }
+
+    pub fn bin_mut(&mut self, bin_id: i32) -> Result<&mut Bin> {
+        let offset = self.offset(bin_id)?;
+        Ok(&mut self.bins[offset])
+    }
+
+    /// LP amount of token A (or B) across the bins with LPs.
+    pub fn lp_holdings(&self, in_a: bool) -> u128 {
+        self.bins.iter().map(|bin| bin.lp_holding(in_a)).sum()
+    }
+
+    /// Splits `fee` of token A (or B) across the bins with LPs, in proportion
+    /// to the LP amount of that token each holds. Fails if no bin holds any.
+    pub fn accrue_fee_by_holdings(&mut self, fee: u64, fee_in_a: bool) -> Result<()> {
+        if fee == 0 {
+            return Ok(());
+        }
+        let mut remaining_holdings = self.lp_holdings(fee_in_a);
+        if remaining_holdings == 0 {
+            return err!(DexError::NoLiquidityForFee);
+        }
+        // Each bin's share is taken from what is left, so the last bin with
+        // holdings receives the rounding remainder and the whole fee lands.
+        let mut remaining_fee = fee;
+        for bin in self.bins.iter_mut() {
+            let held = bin.lp_holding(fee_in_a);
+            if held == 0 {
+                continue;
+            }
+            let share = (remaining_fee as u128 * held / remaining_holdings) as u64;
+            bin.accrue_fee(share, fee_in_a);
+            remaining_fee -= share;
+            remaining_holdings -= held;
+        }
+        Ok(())
+    }
+
+    fn offset(&self, bin_id: i32) -> Result<usize> {
+        if !self.contains(bin_id) {
+            return err!(DexError::InvalidBinArray);
+        }
+        Ok((bin_id as i64 - self.index * BIN_ARRAY_SIZE as i64) as usize)
+    }
+}
+
+/// One-sided order resting in a single bin. Its shares claim a pro rata part
+/// of the bin's unfilled orders on its side for as long as its round lasts.
+#[account]
+pub struct LimitOrder {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub order_id: u64,
+    pub bin_id: i32,
+    pub sell_a: bool,
+    pub round: u32,
+    pub shares: u128,
+    /// Input token the shares were worth when placed.
+    pub amount: u64,
+    /// Input token filled as of the last claim.
+    pub filled: u64,
+    pub bump: u8,
+}
+
+impl LimitOrder {
+    pub const SIZE: usize = 32 + 32 + 8 + 4 + 1 + 4 + 16 + 8 + 8 + 1;
+
+    /// Unfilled input behind the shares. Rounded up when counting fills, so
+    /// orders never claim more than swaps took from them.
+    pub fn unfilled(&self, bin: &Bin, round_up: bool) -> Result<u64> {
+        let (unfilled, supply, round) = bin.orders(self.sell_a);
+        if round != self.round || supply == 0 {
+            return Ok(0);
+        }
+        let owed = (unfilled as u128)
+            .checked_mul(self.shares)
+            .ok_or(DexError::Overflow)?;
+        let owed = if round_up {
+            owed.div_ceil(supply)
+        } else {
+            owed / supply
+        };
+        u64::try_from(owed).map_err(|_| error!(DexError::Overflow))
+    }
+
+    /// Records fills since the last call and returns what they earned in the
+    /// other token at the bin price, rounded down. Proceeds come out of what
+    /// swaps paid into the bin for its side's fills, so claims never exceed
+    /// it.
+    pub fn settle_fills(&mut self, pool: &Pool, bin: &mut Bin) -> Result<u64> {
+        let filled = self
+            .amount
+            .saturating_sub(self.unfilled(bin, true)?)
+            .max(self.filled);
+        let earned = quote_out(pool, self.bin_id, filled, self.sell_a)?
+            .checked_sub(quote_out(pool, self.bin_id, self.filled, self.sell_a)?)
+            .ok_or(DexError::Overflow)?;
+        let received = bin.order_proceeds_mut(self.sell_a);
+        let proceeds = earned.min(*received);
+        *received -= proceeds;
+        self.filled = filled;
+        Ok(proceeds)
+    }
+
+    /// Takes the order's unfilled input out of the bin and returns it.
+    pub fn withdraw_unfilled(&mut self, bin: &mut Bin) -> Result<u64> {
+        let remaining = self
+            .amount
+            .checked_sub(self.filled)
+            .ok_or(DexError::Overflow)?;
+        let refund = self.unfilled(bin, false)?.min(remaining);
+        let (unfilled, supply, round) = bin.orders_mut(self.sell_a);
+        if *round == self.round {
+            *unfilled = unfilled.checked_sub(refund).ok_or(DexError::Overflow)?;
+            *supply = supply.checked_sub(self.shares).ok_or(DexError::Overflow)?;
+        }
+        self.shares = 0;
+        Ok(refund)
+    }
+}
+
+/// Ring buffer of cumulative active-bin prices. Each swap records, for the
+/// first swap in a slot, the price that was active since the previous entry.
+#[account]
+pub struct Observations {
+    pub pool: Pubkey,
+    /// Position of the newest observation.
+    pub index: u16,
+    pub bump: u8,
+    pub observations: Vec<Observation>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Observation {
+    pub slot: u64,
+    /// Sum of the Q64.64 active bin price over every slot, wrapping.
+    pub cumulative_price: u128,
+}
+
+impl Observation {
+    pub const SIZE: usize = 8 + 16;
+}
+
+impl Observations {
+    pub fn space(capacity: usize) -> usize {
+        32 + 2 + 1 + 4 + Observation::SIZE * capacity
+    }
+
+    fn newest(&self) -> Observation {
+        self.observations[self.index as usize]
+    }
+
+    /// Accrues `price`, active since the newest observation, up to `slot`.
+    pub fn record(&mut self, slot: u64, price: u128) {
+        let newest = self.newest();
+        if slot <= newest.slot {
+            return;
+        }
+        let index = (self.index as usize + 1) % self.observations.len();
+        self.observations[index] = Observation {
+            slot,
+            cumulative_price: newest
+                .cumulative_price
+                .wrapping_add(price.wrapping_mul((slot - newest.slot) as u128)),
+        };
+        self.index = index as u16;
+    }
+
+    /// Reorders oldest first and appends empty entries, which the ring then
+    /// fills before overwriting history.
+    pub fn grow(&mut self, capacity: usize) {
+        self.observations.rotate_left(self.index as usize + 1);
+        self.index = (self.observations.len() - 1) as u16;
+        self.observations.resize(capacity, Observation::default());
+    }
+
+    /// Cumulative price at `slot`, interpolated between observations and
+    /// extrapolated past the newest one at `current_price`.
+    pub fn cumulative_at(&self, slot: u64, current_price: u128) -> Result<u128> {
+        let mut newer = self.newest();
+        if slot >= newer.slot {
+            return Ok(newer
+                .cumulative_price
+                .wrapping_add(current_price.wrapping_mul((slot - newer.slot) as u128)));
+        }
+        let len = self.observations.len();
+        for back in 1..len {
+            let older = self.observations[(self.index as usize + len - back) % len];
+            // Grown buffers hold empty entries until the ring reaches them.
+            if older.slot == 0 {
+                break;
+            }
+            if older.slot <= slot {
+                let elapsed = (newer.slot - older.slot) as u128;
+                let price = newer.cumulative_price.wrapping_sub(older.cumulative_price) / elapsed;
+                return Ok(older
+                    .cumulative_price
+                    .wrapping_add(price.wrapping_mul((slot - older.slot) as u128)));
+            }
+            newer = older;
+        }
+        err!(DexError::ObservationTooOld)
+    }
+
+    pub fn twap(&self, now: u64, window_slots: u64, current_price: u128) -> Result<u128> {
+        if window_slots == 0 {
+            return err!(DexError::InvalidAmount);
+        }
+        let start = now
+            .checked_sub(window_slots)
+            .ok_or(DexError::ObservationTooOld)?;
+        let end_cumulative = self.cumulative_at(now, current_price)?;
+        let start_cumulative = self.cumulative_at(start, current_price)?;
+        Ok(end_cumulative.wrapping_sub(start_cumulative) / window_slots as u128)
+    }
+}
+
+#[account]
+pub struct Stake {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub staked_at_slot: u64,
+    pub rebate_bps: u16,
+    pub active: bool,
+    pub bump: u8,
+}
+
+impl Stake {
+    pub const SIZE: usize = 32 + 32 + 32 + 8 + 2 + 1 + 1;
+}
+
+#[event]
+pub struct HouseFeesWithdrawn {
+    pub pool: Pubkey,
+    pub treasury: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct ProtocolFeeShareUpdated {
+    pub old_share_bps: u16,
+    pub new_share_bps: u16,
+}
+
+#[event]
+pub struct PoolProtocolFeeShareUpdated {
+    pub pool: Pubkey,
+    pub old_share_bps: u16,
+    pub new_share_bps: u16,
+    pub house_fee_bps: u16,
+    pub lp_fee_bps: u16,
+}
+
+#[event]
+pub struct DynamicFeeParamsUpdated {
+    pub pool: Pubkey,
+    pub filter_period: u16,
+    pub decay_period: u16,
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub max_volatility_accumulator: u32,
+    pub max_total_fee_bps: u16,
+}
+
+#[event]
+pub struct PoolOraclesUpdated {
+    pub pool: Pubkey,
+    pub oracle_kind: u8,
+    pub oracle_a: Pubkey,
+    pub oracle_b: Pubkey,
+    pub max_oracle_age_secs: u32,
+    pub max_oracle_confidence_bps: u16,
+}
+
+#[event]
+pub struct ReferencePricesUpdated {
+    pub pool: Pubkey,
+    pub old_token_a_price: u128,
+    pub old_token_b_price: u128,
+    pub token_a_price: u128,
+    pub token_b_price: u128,
+    pub slot: u64,
+}
+
+#[event]
+pub struct DepthRatiosUpdated {
+    pub pool: Pubkey,
+    pub old_depth_ratio_above_bps: u32,
+    pub old_depth_ratio_below_bps: u32,
+    pub depth_ratio_above_bps: u32,
+    pub depth_ratio_below_bps: u32,
+}
+
+#[event]
+pub struct FlashLoanFeeUpdated {
+    pub pool: Pubkey,
+    pub old_fee_bps: u16,
+    pub new_fee_bps: u16,
+}
+
+#[event]
+pub struct FlashLoanBorrowed {
+    pub pool: Pubkey,
+    pub borrower: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+    pub in_a: bool,
+}
+
+#[event]
+pub struct FlashLoanRepaid {
+    pub pool: Pubkey,
+    pub borrower: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+    pub in_a: bool,
+}
+
+#[event]
+pub struct ConfigInitialized {
+    pub config: Pubkey,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct AdminUpdated {
+    pub old_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
+
+#[event]
+pub struct TreasuryUpdated {
+    pub old_treasury: Pubkey,
+    pub new_treasury: Pubkey,
+}
+
+#[event]
+pub struct RegistryInitialized {
+    pub registry: Pubkey,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub pool_id: u64,
+    pub creator: Pubkey,
+    pub token_a_mint: Pubkey,
+    pub token_b_mint: Pubkey,
+    pub fee_bps: u16,
+    pub bin_step_bps: u16,
+    pub active_bin_id: i32,
+}
+
+#[event]
+pub struct BinArrayInitialized {
+    pub pool: Pubkey,
+    pub bin_array: Pubkey,
+    pub index: i64,
+}
+
+#[event]
+pub struct ObservationsInitialized {
+    pub pool: Pubkey,
+    pub observations: Pubkey,
+}
+
+#[event]
+pub struct ObservationsGrown {
+    pub pool: Pubkey,
+    pub old_capacity: u16,
+    pub new_capacity: u16,
+}
+
+#[event]
+pub struct PositionCreated {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub lp_mint: Pubkey,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct LpNftStaked {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct LpNftUnstaked {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct LiquidityAdded {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct LiquidityRemoved {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub bps: u16,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+/// Paid to the LP NFT holder, who may not be the position owner.
+#[event]
+pub struct FeesClaimed {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub holder: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+/// Amounts include fees that were still owed to the position.
+#[event]
+pub struct PositionClosed {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct LimitOrderPlaced {
+    pub pool: Pubkey,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub bin_id: i32,
+    pub sell_a: bool,
+    pub amount: u64,
+}
+
+/// `filled` is the order's total filled input so far.
+#[event]
+pub struct LimitOrderClaimed {
+    pub pool: Pubkey,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub filled: u64,
+    pub proceeds: u64,
+}
+
+#[event]
+pub struct LimitOrderCancelled {
+    pub pool: Pubkey,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub refund: u64,
+    pub proceeds: u64,
+}
+
+/// Emitted by both `swap` and `swap_exact_out`; `amount_in` includes fees.
+#[event]
+pub struct Swapped {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub swap_for_b: bool,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub active_bin_id: i32,
+}
+
+#[event]
+pub struct RouteSwapped {
+    pub user: Pubkey,
+    pub mint_in: Pubkey,
+    pub mint_out: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub hops: u8,
+}
+
+#[event]
+pub struct LegacyPricesMigrated {
+    pub account: Pubkey,
+}
+
+#[error_code]
+pub enum DexError {
+    #[msg("Config already initialized")]
+    AlreadyInitialized,
+    #[msg("Missing PDA bump")]
+    MissingBump,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Invalid fee parameters")]
+    InvalidFee,
+    #[msg("Token mint must be frozen")]
+    MintNotFrozen,
+    #[msg("Token mint must not be frozen")]
+    MintMustBeUnfrozen,
+    #[msg("Invalid token kind")]
+    InvalidTokenKind,
+    #[msg("Invalid guarantee policy")]
+    InvalidGuaranteePolicy,
+    #[msg("Already staked")]
+    AlreadyStaked,
+    #[msg("Not staked")]
+    NotStaked,
+    #[msg("Invalid position")]
+    InvalidPosition,
+    #[msg("Arithmetic overflow")]
+    Overflow,
+    #[msg("Invalid price inputs")]
+    InvalidPrice,
+    #[msg("Position already initialized")]
+    PositionAlreadyInitialized,
+    #[msg("Invalid price range")]
+    InvalidPriceRange,
+    #[msg("Invalid function spec")]
+    InvalidFunctionSpec,
+    #[msg("Function produces a negative weight in the position range")]
+    NegativeFunctionWeight,
+    #[msg("Function is undefined or overflows in the position range")]
+    FunctionDomainError,
+    #[msg("Function weights are all zero in the position range")]
+    ZeroFunctionWeights,
+    #[msg("Invalid amount")]
+    InvalidAmount,
+    #[msg("One-sided deposit not allowed by depth rule")]
+    OneSidedNotAllowed,
+    #[msg("Bin id out of range")]
+    InvalidBinId,
+    #[msg("Bin array does not cover the requested bins")]
+    InvalidBinArray,
+    #[msg("Too many bins in position range")]
+    TooManyBins,
+    #[msg("No bins available for deposit side")]
+    NoBinsForDeposit,
+    #[msg("Swap would cross more than MAX_BINS_PER_SWAP bins")]
+    SwapBinLimitExceeded,
+    #[msg("Swap input exceeds max_amount_in")]
+    MaxAmountInExceeded,
+    #[msg("Swap output below min_amount_out")]
+    SlippageExceeded,
+    #[msg("Active bin moved beyond the allowed slippage")]
+    ActiveBinSlippageExceeded,
+    #[msg("Transaction expired")]
+    TransactionExpired,
+    #[msg("Protocol fee share exceeds MAX_PROTOCOL_FEE_SHARE_BPS")]
+    ProtocolFeeShareTooHigh,
+    #[msg("Invalid dynamic fee parameters")]
+    InvalidDynamicFeeParams,
+    #[msg("Oracle account is missing, mismatched, or malformed")]
+    InvalidOracle,
+    #[msg("Oracle price is older than max_oracle_age_secs")]
+    StaleOraclePrice,
+    #[msg("Oracle confidence interval exceeds max_oracle_confidence_bps")]
+    OracleConfidenceTooWide,
+    #[msg("Price moves more than MAX_REFERENCE_PRICE_DEVIATION_BPS")]
+    PriceDeviationTooLarge,
+    #[msg("Reference prices were updated too recently")]
+    PriceUpdateTooFrequent,
+    #[msg("Bin step must be between 1 and MAX_BIN_STEP_BPS")]
+    InvalidBinStep,
+    #[msg("Account is not a legacy pool or position")]
+    InvalidMigration,
+    #[msg("Depth ratio exceeds MAX_DEPTH_RATIO_BPS")]
+    InvalidDepthRatio,
+    #[msg("Swap route accounts do not chain from the input to the output mint")]
+    InvalidRoute,
+    #[msg("Flash loan fee exceeds MAX_FLASH_LOAN_FEE_BPS")]
+    InvalidFlashLoanFee,
+    #[msg("Pool already has an outstanding flash loan")]
+    FlashLoanActive,
+    #[msg("Flash loan is not repaid later in the transaction")]
+    FlashLoanNotRepaid,
+    #[msg("Pool has no outstanding flash loan")]
+    NoFlashLoan,
+    #[msg("Vault is not the pool's vault for this token")]
+    InvalidVault,
+    #[msg("Observation capacity must grow and stay within MAX_OBSERVATIONS")]
+    InvalidObservationCapacity,
+    #[msg("TWAP window reaches past the oldest observation")]
+    ObservationTooOld,
+    #[msg("Limit orders sell token A above the active bin and token B below it")]
+    InvalidLimitOrderBin,
+    #[msg("No LP in the bin array holds the token the fee is paid in")]
+    NoLiquidityForFee,
+}
+
+/// Q64.64 price of one token in another from their Q64.64 USD prices.
+pub fn div_price(price: u128, reference_price: u128) -> Result<u128> {
+    if price == 0 || reference_price == 0 {
+        return err!(DexError::InvalidPrice);
+    }
+    // (price << 64) / reference_price by long division over the low 64 bits.
+    let mut quotient = price / reference_price;
+    let mut remainder = price % reference_price;
+    for _ in 0..64 {
+        let carry = remainder >> 127;
+        remainder <<= 1;
+        quotient = quotient.checked_mul(2).ok_or(DexError::Overflow)?;
+        if carry == 1 || remainder >= reference_price {
+            remainder = remainder.wrapping_sub(reference_price);
+            quotient |= 1;
+        }
+    }
+    Ok(quotient)
+}
+
+/// Q64.64 price of one base unit of token A in base units of token B, which
+/// is how bins price swaps, from the two Q64.64 USD prices of whole tokens.
+fn base_unit_price(
+    (price_a, price_b): (u128, u128),
+    (token_a_decimals, token_b_decimals): (u8, u8),
+) -> Result<u128> {
+    // Scale before dividing so truncation only happens once.
+    let scale = decimal_scale(token_a_decimals.abs_diff(token_b_decimals))?;
+    if token_b_decimals >= token_a_decimals {
+        div_price(
+            price_a.checked_mul(scale).ok_or(DexError::Overflow)?,
+            price_b,
+        )
+    } else {
+        div_price(
+            price_a,
+            price_b.checked_mul(scale).ok_or(DexError::Overflow)?,
+        )
+    }
+}
+
+/// USD values of two raw amounts, both scaled to the larger decimals so
+/// mints with different decimals compare correctly. Q64.64 up to that scale.
+fn pool_values(
+    (amount_a, amount_b): (u64, u64),
+    (price_a, price_b): (u128, u128),
+    (token_a_decimals, token_b_decimals): (u8, u8),
+) -> Result<(u128, u128)> {
+    let decimals = token_a_decimals.max(token_b_decimals);
+    let value = |amount: u64, price: u128, token_decimals: u8| -> Result<u128> {
+        let amount = (amount as u128)
+            .checked_mul(decimal_scale(decimals - token_decimals)?)
+            .ok_or(DexError::Overflow)?;
+        mul_shr_64(amount, price).ok_or(error!(DexError::Overflow))
+    };
+    Ok((
+        value(amount_a, price_a, token_a_decimals)?,
+        value(amount_b, price_b, token_b_decimals)?,
+    ))
+}
+
+fn decimal_scale(decimals: u8) -> Result<u128> {
+    10u128
+        .checked_pow(decimals as u32)
+        .ok_or(error!(DexError::Overflow))
+}
+
+/// Q64.64 price from a price in cents.
+pub fn cents_to_price(cents: u64) -> u128 {
+    ((cents as u128) << 64) / 100
+}
+
+/// Offset of the two u64 cent prices in a legacy account, discriminator included.
+const LEGACY_POOL_PRICES_OFFSET: usize = 8 + 8 + 32 * 3 + 4 + 2 * 4 + 1 + 2 + 32;
+const LEGACY_POSITION_PRICES_OFFSET: usize = 8 + 32 + 32 + 8 + 32;
+
+/// New account data for a legacy `Pool` or `Position` with its cent prices
+/// widened to Q64.64. Pools also get the fields added since appended: mint
+/// decimals from `mints`, given as (key, decimals) for token A and token B,
+/// and default depth ratios and flash loan fee.
+fn widen_legacy_prices(legacy: &[u8], mints: Option<[(Pubkey, u8); 2]>) -> Result<Vec<u8>> {
+    let discriminator = legacy.get(..8).ok_or(DexError::InvalidMigration)?;
+    let (offset, size, trailer) = if discriminator == Pool::DISCRIMINATOR {
+        let [(token_a_mint, token_a_decimals), (token_b_mint, token_b_decimals)] =
+            mints.ok_or(DexError::InvalidMigration)?;
+        // The mints follow pool_id and creator in every layout.
+        let mint_at = |at: usize| legacy.get(at..at + 32);
+        if mint_at(8 + 8 + 32) != Some(token_a_mint.as_ref())
+            || mint_at(8 + 8 + 32 + 32) != Some(token_b_mint.as_ref())
+        {
+            return err!(DexError::InvalidMigration);
+        }
+        let mut trailer = vec![token_a_decimals, token_b_decimals];
+        trailer.extend_from_slice(&DEFAULT_DEPTH_RATIO_BPS.to_le_bytes());
+        trailer.extend_from_slice(&DEFAULT_DEPTH_RATIO_BPS.to_le_bytes());
+        trailer.extend_from_slice(&DEFAULT_FLASH_LOAN_FEE_BPS.to_le_bytes());
+        // No flash loan outstanding and no limit orders placed.
+        trailer.extend_from_slice(&[0; 8 + 8 + 1 + 8]);
+        (LEGACY_POOL_PRICES_OFFSET, 8 + Pool::SIZE, trailer)
+    } else if discriminator == Position::DISCRIMINATOR {
+        (LEGACY_POSITION_PRICES_OFFSET, 8 + Position::SIZE, vec![])
+    } else {
+        return err!(DexError::InvalidMigration);
+    };
+    if legacy.len() + 16 + trailer.len() != size {
+        return err!(DexError::InvalidMigration);
+    }
+
+    let read = |at: usize| u64::from_le_bytes(legacy[at..at + 8].try_into().unwrap());
+    let mut migrated = Vec::with_capacity(size);
+    migrated.extend_from_slice(&legacy[..offset]);
+    migrated.extend_from_slice(&cents_to_price(read(offset)).to_le_bytes());
+    migrated.extend_from_slice(&cents_to_price(read(offset + 8)).to_le_bytes());
+    migrated.extend_from_slice(&legacy[offset + 16..]);
+    migrated.extend_from_slice(&trailer);
+    Ok(migrated)
+}
+
+/// Price of `bin_id` in Q64.64: (1 + bin_step_bps / 10_000)^bin_id.
+pub fn price_from_bin_id(bin_id: i32, bin_step_bps: u16) -> Result<u128> {
+    match checked_bin_price(bin_id, bin_step_bps) {
+        Some(0) => err!(DexError::InvalidPrice),
+        Some(price) => Ok(price),
+        None => err!(DexError::Overflow),
+    }
+}
+
+/// Highest bin whose price does not exceed `price` (Q64.64).
+/// Bin holding a position range bound of `price` (Q64.64 USD per token A),
+/// converted at `price_b`, token B's current USD price: the oracle price on
+/// pools with oracles, the stored price otherwise.
+fn range_bin_id(pool: &Pool, price: u128, price_b: u128) -> Result<i32> {
+    bin_id_from_price(
+        base_unit_price(
+            (price, price_b),
+            (pool.token_a_decimals, pool.token_b_decimals),
+        )?,
+        pool.bin_step_bps,
+    )
+}
+
+pub fn bin_id_from_price(price: u128, bin_step_bps: u16) -> Result<i32> {
+    if price == 0 || bin_step_bps == 0 {
+        return err!(DexError::InvalidPrice);
+    }
+    let at_or_below =
+        |bin_id| matches!(checked_bin_price(bin_id, bin_step_bps), Some(p) if p <= price);
+    let (mut low, mut high) = (-MAX_BIN_ID, MAX_BIN_ID);
+    if !at_or_below(low) {
+        return err!(DexError::InvalidPrice);
+    }
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if at_or_below(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+/// Exponentiation by squaring in Q64.64, rounding down at each step. Returns
+/// `None` when the price overflows or `bin_id` is beyond `MAX_BIN_ID`.
+fn checked_bin_price(bin_id: i32, bin_step_bps: u16) -> Option<u128> {
+    if bin_id.unsigned_abs() > MAX_BIN_ID as u32 {
+        return None;
+    }
+    let step = bin_step_bps as u128;
+    let bps = BPS_DENOMINATOR as u128;
+    // Negative bins use the inverse base so both sides round the same way.
+    let mut base = if bin_id >= 0 {
+        Q64 * (bps + step) / bps
+    } else {
+        Q64 * bps / (bps + step)
+    };
+    let mut exponent = bin_id.unsigned_abs();
+    let mut price = Q64;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            price = mul_shr_64(price, base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = mul_shr_64(base, base)?;
+        }
+    }
+    Some(price)
+}
+
+fn bin_price(pool: &Pool, bin_id: i32) -> Result<u128> {
+    price_from_bin_id(bin_id, pool.bin_step_bps)
+}
+
+fn bin_array_index(bin_id: i32) -> i64 {
+    (bin_id as i64).div_euclid(BIN_ARRAY_SIZE as i64)
+}
+
+fn bin_value(pool: &Pool, bin_id: i32, amount_a: u64, amount_b: u64) -> Result<u128> {
+    // Value in token B units, Q32.32: token A at the bin price.
+    let value_a = (amount_a as u128)
+        .checked_mul(bin_price(pool, bin_id)?)
+        .ok_or(DexError::Overflow)?
+        >> 32;
+    let value_b = (amount_b as u128) << 32;
+    value_a
+        .checked_add(value_b)
+        .ok_or(error!(DexError::Overflow))
+}
+
+fn validate_position_bins<B: Deref<Target = BinArray>>(
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+    bin_arrays: &[B],
+) -> Result<()> {
+    if lower_bin_id > upper_bin_id {
+        return err!(DexError::InvalidPriceRange);
+    }
+    let width = (upper_bin_id as i64) - (lower_bin_id as i64) + 1;
+    if width > MAX_BINS_PER_DEPOSIT as i64 {
+        return err!(DexError::TooManyBins);
+    }
+    // A position spans one bin array, or two consecutive ones.
+    let covered = match bin_arrays {
+        [bin_array] => {
+            bin_array.index == bin_array_index(lower_bin_id)
+                && bin_array.index == bin_array_index(upper_bin_id)
+        }
+        [bin_array_lower, bin_array_upper] => {
+            bin_array_lower.index == bin_array_index(lower_bin_id)
+                && bin_array_upper.index == bin_array_lower.index + 1
+        }
+        _ => false,
+    };
+    if !covered {
+        return err!(DexError::InvalidBinArray);
+    }
+    Ok(())
+}
+
+/// Borrows a position's bin arrays. A position that fits in one bin array
+/// passes it as both `bin_array_lower` and `bin_array_upper`, and it is
+/// borrowed once.
+fn load_position_bin_arrays<'a>(
+    bin_array_lower: &'a AccountLoader<BinArray>,
+    bin_array_upper: &'a AccountLoader<BinArray>,
+) -> Result<Vec<RefMut<'a, BinArray>>> {
+    let mut bin_arrays = vec![bin_array_lower.load_mut()?];
+    if bin_array_upper.key() != bin_array_lower.key() {
+        bin_arrays.push(bin_array_upper.load_mut()?);
+    }
+    Ok(bin_arrays)
+}
+
+/// Fails while a position's LP NFT is staked. `stake` is the position's stake
+/// PDA, which is not owned by this program until the position is first staked.
+fn check_not_staked(stake: &AccountInfo) -> Result<()> {
+    if stake.owner != &crate::ID {
+        return Ok(());
+    }
+    if Stake::try_deserialize(&mut &stake.try_borrow_data()?[..])?.active {
+        return err!(DexError::AlreadyStaked);
+    }
+    Ok(())
+}
+
+/// Fails while `pool` has a flash loan outstanding. Swaps and liquidity
+/// changes wait for the repay, so the bin array that earns the fee keeps its
+/// LPs and stays active in between.
+fn check_no_flash_loan(pool: &Pool) -> Result<()> {
+    if pool.flash_loan_amount != 0 {
+        return err!(DexError::FlashLoanActive);
+    }
+    Ok(())
+}
+
+fn check_deadline(expires_at_slot: u64) -> Result<()> {
+    check_deadline_at(Clock::get()?.slot, expires_at_slot)
+}
+
+/// `expires_at_slot` is the last slot the transaction may land in.
+fn check_deadline_at(slot: u64, expires_at_slot: u64) -> Result<()> {
+    if slot > expires_at_slot {
+        return err!(DexError::TransactionExpired);
+    }
+    Ok(())
+}
+
+fn check_active_bin_slippage(
+    pool: &Pool,
+    expected_active_bin_id: i32,
+    max_active_bin_slippage: u32,
+) -> Result<()> {
+    let drift = (pool.active_bin_id as i64 - expected_active_bin_id as i64).unsigned_abs();
+    if drift > max_active_bin_slippage as u64 {
+        return err!(DexError::ActiveBinSlippageExceeded);
+    }
+    Ok(())
+}
+
+fn validate_token_kind(kind: u8, mint: &Account<Mint>, label: &str) -> Result<()> {
     match kind {
         // 1 = ERC20 proxy (frozen required)
         1 => {
@@ -867,169 +3866,2323 @@ fn validate_token_kind(kind: u8, mint: &Account<Mint>, label: &str) -> Result<()
                 return err!(DexError::MintNotFrozen);
             }
         }
-        // 2 = Fiat/Gold proxy (unfrozen required)
-        2 => {
-            if mint.freeze_authority.is_some() {
-                msg!("{} mint must not be frozen for fiat/gold proxy", label);
-                return err!(DexError::MintMustBeUnfrozen);
+        // 2 = Fiat/Gold proxy (unfrozen required)
+        2 => {
+            if mint.freeze_authority.is_some() {
+                msg!("{} mint must not be frozen for fiat/gold proxy", label);
+                return err!(DexError::MintMustBeUnfrozen);
+            }
+        }
+        // 3 = Wrapped SOL (no freeze requirement)
+        3 => {}
+        // 4 = USDC (frozen required)
+        4 => {
+            if mint.freeze_authority.is_none() {
+                msg!("{} mint must be frozen for USDC", label);
+                return err!(DexError::MintNotFrozen);
+            }
+        }
+        // 5 = EUR token (frozen required)
+        5 => {
+            if mint.freeze_authority.is_none() {
+                msg!("{} mint must be frozen for EUR token", label);
+                return err!(DexError::MintNotFrozen);
+            }
+        }
+        // 6 = Commodity proxy (frozen required)
+        6 => {
+            if mint.freeze_authority.is_none() {
+                msg!("{} mint must be frozen for commodity proxy", label);
+                return err!(DexError::MintNotFrozen);
+            }
+        }
+        // 7 = Native token (frozen required)
+        7 => {
+            if mint.freeze_authority.is_none() {
+                msg!("{} mint must be frozen for native token", label);
+                return err!(DexError::MintNotFrozen);
+            }
+        }
+        _ => return err!(DexError::InvalidTokenKind),
+    }
+
+    Ok(())
+}
+
+fn validate_protocol_fee_share(protocol_fee_share_bps: u16) -> Result<()> {
+    if protocol_fee_share_bps > MAX_PROTOCOL_FEE_SHARE_BPS {
+        return err!(DexError::ProtocolFeeShareTooHigh);
+    }
+    Ok(())
+}
+
+/// Splits `fee_bps` into `(house_fee_bps, lp_fee_bps)`, rounding the house
+/// share down.
+fn split_fee_bps(fee_bps: u16, protocol_fee_share_bps: u16) -> (u16, u16) {
+    let house_fee_bps = (fee_bps as u64 * protocol_fee_share_bps as u64 / BPS_DENOMINATOR) as u16;
+    (house_fee_bps, fee_bps - house_fee_bps)
+}
+
+fn validate_guarantee_policy(
+    policy: u8,
+    allowed_assets_mask: u16,
+    guarantee_mint: Pubkey,
+) -> Result<()> {
+    match policy {
+        // 0 = fixed guarantee mint
+        0 => {
+            if guarantee_mint == Pubkey::default() || allowed_assets_mask != 0 {
+                return err!(DexError::InvalidGuaranteePolicy);
+            }
+        }
+        // 1 = user choice from allowed assets mask
+        1 => {
+            if allowed_assets_mask == 0 || guarantee_mint != Pubkey::default() {
+                return err!(DexError::InvalidGuaranteePolicy);
+            }
+        }
+        _ => return err!(DexError::InvalidGuaranteePolicy),
+    }
+    Ok(())
+}
+
+fn validate_function_spec(function_type: u8, params: &[i64; 5]) -> Result<()> {
+    match function_type {
+        FUNCTION_LINEAR => {
+            // f(x) = m(x - x0) + y0
+            // params: [m, x0, y0, unused, unused]
+            let _m = params[0];
+            let _x0 = params[1];
+            let _y0 = params[2];
+        }
+        FUNCTION_LOG => {
+            // g(x) = A * log_B(C(-x + h)) + k
+            // params: [A, B, C, h, k]
+            if params[1] == 0 || params[2] == 0 {
+                return err!(DexError::InvalidFunctionSpec);
+            }
+        }
+        FUNCTION_SPOT => {
+            // w(d) = 1
+        }
+        FUNCTION_CURVE => {
+            // w(d) = exp(-(d - 1)^2 / (2 * sigma^2))
+            // params: [sigma, unused, unused, unused, unused]
+            if params[0] <= 0 {
+                return err!(DexError::InvalidFunctionSpec);
+            }
+        }
+        FUNCTION_BIDASK => {
+            // w(d) = 1 + edge_boost * (1 - exp(-(d - 1)^2 / (2 * sigma^2)))
+            // params: [sigma, edge_boost, unused, unused, unused]
+            if params[0] <= 0 || params[1] < PARAM_SCALE {
+                return err!(DexError::InvalidFunctionSpec);
+            }
+        }
+        FUNCTION_EXPONENTIAL => {
+            // w(d) = ratio^d
+            // params: [ratio, unused, unused, unused, unused]
+            if params[0] <= 0 {
+                return err!(DexError::InvalidFunctionSpec);
+            }
+        }
+        FUNCTION_POWER => {
+            // w(d) = 1 / (d + c)^p
+            // params: [p, c, unused, unused, unused]
+            if params[0] <= 0 || params[1] < 0 {
+                return err!(DexError::InvalidFunctionSpec);
+            }
+        }
+        FUNCTION_WALL_DECAY => {
+            // w(d) = 1 for d <= wall_bins, then ratio^(d - wall_bins)
+            // params: [wall_bins, ratio, unused, unused, unused]
+            if params[0] < 0 || params[1] <= 0 {
+                return err!(DexError::InvalidFunctionSpec);
+            }
+        }
+        FUNCTION_PRESET_POWER
+        | FUNCTION_PRESET_EXP
+        | FUNCTION_PRESET_LOG
+        | FUNCTION_PRESET_SIGMOID => validate_preset(function_type, params)?,
+        _ => return err!(DexError::InvalidFunctionSpec),
+    }
+
+    Ok(())
+}
+
+fn validate_preset(function_type: u8, params: &[i64; 5]) -> Result<()> {
+    let [base, amp, shape, midpoint, _] = *params;
+    if !(0..=PRESET_MAX_WEIGHT).contains(&base)
+        || !(0..=PRESET_MAX_WEIGHT).contains(&amp)
+        || base + amp == 0
+    {
+        return err!(DexError::InvalidFunctionSpec);
+    }
+    let shape_ok = match function_type {
+        // f(x) = x^p
+        FUNCTION_PRESET_POWER => shape > 0 && shape <= PRESET_MAX_STEEPNESS,
+        // f(x) = expm1(kx) / expm1(k)
+        FUNCTION_PRESET_EXP => shape != 0 && shape.abs() <= PRESET_MAX_STEEPNESS,
+        // f(x) = log1p(ax) / log1p(a)
+        FUNCTION_PRESET_LOG => shape > 0 && shape <= PRESET_MAX_LOG_SHAPE,
+        // logistic(k(x - m)) rescaled so f(0) = 0 and f(1) = 1
+        FUNCTION_PRESET_SIGMOID => {
+            shape > 0 && shape <= PRESET_MAX_STEEPNESS && (0..=PARAM_SCALE).contains(&midpoint)
+        }
+        _ => false,
+    };
+    if !shape_ok {
+        return err!(DexError::InvalidFunctionSpec);
+    }
+    Ok(())
+}
+
+fn check_price_deviation(old_price: u128, new_price: u128) -> Result<()> {
+    if new_price == 0 {
+        return err!(DexError::InvalidPrice);
+    }
+    // old_price * limit / BPS, split so the product cannot overflow.
+    let (limit, bps) = (
+        MAX_REFERENCE_PRICE_DEVIATION_BPS as u128,
+        BPS_DENOMINATOR as u128,
+    );
+    let allowed = old_price / bps * limit + old_price % bps * limit / bps;
+    if old_price.abs_diff(new_price) > allowed {
+        return err!(DexError::PriceDeviationTooLarge);
+    }
+    Ok(())
+}
+
+/// Q64.64 USD token prices used to value deposits: the pool's oracles when it
+/// has them, otherwise the static prices set at `create_pool`.
+fn current_prices<'info>(
+    pool: &Pool,
+    oracle_a: Option<&AccountInfo<'info>>,
+    oracle_b: Option<&AccountInfo<'info>>,
+) -> Result<(u128, u128)> {
+    if pool.oracle_kind == ORACLE_KIND_NONE {
+        return Ok((pool.token_a_price, pool.token_b_price));
+    }
+    let (oracle_a, oracle_b) = match (oracle_a, oracle_b) {
+        (Some(oracle_a), Some(oracle_b)) => (oracle_a, oracle_b),
+        _ => return err!(DexError::InvalidOracle),
+    };
+    require_keys_eq!(oracle_a.key(), pool.oracle_a, DexError::InvalidOracle);
+    require_keys_eq!(oracle_b.key(), pool.oracle_b, DexError::InvalidOracle);
+
+    let now = Clock::get()?.unix_timestamp;
+    let read = |account: &AccountInfo<'info>| {
+        oracle::read_price(
+            pool.oracle_kind,
+            account,
+            now,
+            pool.max_oracle_age_secs,
+            pool.max_oracle_confidence_bps,
+        )
+    };
+    Ok((read(oracle_a)?, read(oracle_b)?))
+}
+
+/// Adds a deposit to the pool totals. A one-sided deposit is weighed by the
+/// depth rule against the other side of the active bin, over the bin arrays
+/// it is deposited into.
+fn apply_liquidity<B: Deref<Target = BinArray>>(
+    pool: &mut Pool,
+    bin_arrays: &[B],
+    amount_a: u64,
+    amount_b: u64,
+    prices: (u128, u128),
+) -> Result<()> {
+    if amount_a == 0 && amount_b == 0 {
+        return err!(DexError::InvalidAmount);
+    }
+
+    let total_a = pool
+        .total_a_amount
+        .checked_add(amount_a)
+        .ok_or(DexError::Overflow)?;
+    let total_b = pool
+        .total_b_amount
+        .checked_add(amount_b)
+        .ok_or(DexError::Overflow)?;
+
+    if (amount_a == 0) ^ (amount_b == 0) {
+        let (mut above, mut below) = side_reserves(pool.active_bin_id, bin_arrays)?;
+        let side_value = |(amount_a, amount_b)| -> Result<u128> {
+            let (value_a, value_b) = pool_values(
+                (amount_a, amount_b),
+                prices,
+                (pool.token_a_decimals, pool.token_b_decimals),
+            )?;
+            value_a
+                .checked_add(value_b)
+                .ok_or(error!(DexError::Overflow))
+        };
+
+        // Token A deposits land above the active bin, token B below it.
+        if amount_a == 0 {
+            below.1 = below.1.checked_add(amount_b).ok_or(DexError::Overflow)?;
+            check_depth_ratio(
+                side_value(below)?,
+                side_value(above)?,
+                pool.depth_ratio_below_bps,
+            )?;
+        } else {
+            above.0 = above.0.checked_add(amount_a).ok_or(DexError::Overflow)?;
+            check_depth_ratio(
+                side_value(above)?,
+                side_value(below)?,
+                pool.depth_ratio_above_bps,
+            )?;
+        }
+    }
+
+    pool.total_a_amount = total_a;
+    pool.total_b_amount = total_b;
+    Ok(())
+}
+
+/// LP reserves of the bins in `bin_arrays` above and below the active bin,
+/// as `(amount_a, amount_b)` pairs. The active bin's token A is sold as the
+/// price rises, so it counts above; its token B counts below.
+fn side_reserves<B: Deref<Target = BinArray>>(
+    active_bin_id: i32,
+    bin_arrays: &[B],
+) -> Result<((u64, u64), (u64, u64))> {
+    let (mut above, mut below) = ((0u64, 0u64), (0u64, 0u64));
+    for bin_array in bin_arrays {
+        let first_bin_id = bin_array.index * BIN_ARRAY_SIZE as i64;
+        for (offset, bin) in bin_array.bins.iter().enumerate() {
+            let bin_id = first_bin_id + offset as i64;
+            let side_a = if bin_id >= active_bin_id as i64 {
+                &mut above.0
+            } else {
+                &mut below.0
+            };
+            *side_a = side_a.checked_add(bin.amount_a).ok_or(DexError::Overflow)?;
+            let side_b = if bin_id > active_bin_id as i64 {
+                &mut above.1
+            } else {
+                &mut below.1
+            };
+            *side_b = side_b.checked_add(bin.amount_b).ok_or(DexError::Overflow)?;
+        }
+    }
+    Ok((above, below))
+}
+
+/// Rejects a one-sided deposit that leaves its side worth more than
+/// `depth_ratio_bps` of the other side.
+fn check_depth_ratio(this_side: u128, other_side: u128, depth_ratio_bps: u32) -> Result<()> {
+    let this_side = this_side
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(DexError::Overflow)?;
+    let limit = other_side
+        .checked_mul(depth_ratio_bps as u128)
+        .ok_or(DexError::Overflow)?;
+    if depth_ratio_bps == 0 || this_side > limit {
+        return err!(DexError::OneSidedNotAllowed);
+    }
+    Ok(())
+}
+
+/// Requires a top-level `flash_loan_borrow` followed later in the
+/// transaction by a `flash_loan_repay` for `pool`.
+fn check_flash_loan_repaid(instructions: &AccountInfo, pool: &Pubkey) -> Result<()> {
+    use anchor_lang::solana_program::sysvar::instructions::{
+        load_current_index_checked, load_instruction_at_checked,
+    };
+
+    let current = load_current_index_checked(instructions)? as usize;
+    // Called through CPI the current instruction belongs to another program,
+    // which could repay from the same loan it is borrowing.
+    let borrow = load_instruction_at_checked(current, instructions)?;
+    require_keys_eq!(borrow.program_id, crate::ID, DexError::FlashLoanNotRepaid);
+
+    let mut index = current + 1;
+    while let Ok(instruction) = load_instruction_at_checked(index, instructions) {
+        if is_flash_loan_repay(&instruction, pool) {
+            return Ok(());
+        }
+        index += 1;
+    }
+    err!(DexError::FlashLoanNotRepaid)
+}
+
+fn is_flash_loan_repay(
+    instruction: &anchor_lang::solana_program::instruction::Instruction,
+    pool: &Pubkey,
+) -> bool {
+    instruction.program_id == crate::ID
+        && instruction.data.get(..8) == Some(&instruction::FlashLoanRepay::DISCRIMINATOR[..])
+        && instruction.accounts.first().map(|meta| meta.pubkey) == Some(*pool)
+}
+
+/// Vaults a limit order takes its input from and pays its proceeds out of.
+fn order_vaults(pool: &Pool, sell_a: bool) -> (Pubkey, Pubkey) {
+    if sell_a {
+        (pool.vault_a, pool.vault_b)
+    } else {
+        (pool.vault_b, pool.vault_a)
+    }
+}
+
+/// Splits `total` across one side of a position using the side's allocation
+/// function evaluated at each bin's distance from the active bin.
+fn allocate_side(
+    total: u64,
+    function_type: u8,
+    params: &[i64; 5],
+    distances: &[u32],
+) -> Result<Vec<u64>> {
+    if total == 0 {
+        return Ok(vec![0; distances.len()]);
+    }
+    if distances.is_empty() {
+        return err!(DexError::NoBinsForDeposit);
+    }
+    let weights = function_weights(function_type, params, distances)?;
+    allocate_by_weights(total, &weights)
+}
+
+/// Evaluates a side's function at every distance and rejects weight vectors
+/// that cannot be normalized: any negative weight, any point where the
+/// function is undefined or overflows, or all weights zero.
+fn function_weights(function_type: u8, params: &[i64; 5], distances: &[u32]) -> Result<Vec<u128>> {
+    let max_distance = distances.iter().copied().max().unwrap_or(1);
+    let weights = distances
+        .iter()
+        .map(|distance| {
+            let weight = function_weight(function_type, params, *distance, max_distance)?;
+            u128::try_from(weight).map_err(|_| error!(DexError::NegativeFunctionWeight))
+        })
+        .collect::<Result<Vec<u128>>>()?;
+    if !distances.is_empty() && weights.iter().all(|weight| *weight == 0) {
+        return err!(DexError::ZeroFunctionWeights);
+    }
+    Ok(weights)
+}
+
+/// Deterministic largest-remainder split: floor every bin's share, then hand
+/// the remainder out one unit at a time to the largest fractional parts.
+/// Ties go to the bin closest to the active bin (lowest index).
+fn allocate_by_weights(total: u64, weights: &[u128]) -> Result<Vec<u64>> {
+    let total_weight = weights
+        .iter()
+        .try_fold(0u128, |sum, weight| sum.checked_add(*weight))
+        .ok_or(DexError::Overflow)?;
+    if total_weight == 0 {
+        return err!(DexError::ZeroFunctionWeights);
+    }
+
+    let mut amounts = Vec::with_capacity(weights.len());
+    let mut fractions = Vec::with_capacity(weights.len());
+    for weight in weights {
+        let scaled = (total as u128)
+            .checked_mul(*weight)
+            .ok_or(DexError::Overflow)?;
+        amounts.push((scaled / total_weight) as u64);
+        fractions.push(scaled % total_weight);
+    }
+
+    let allocated: u64 = amounts.iter().sum();
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by(|a, b| fractions[*b].cmp(&fractions[*a]).then(a.cmp(b)));
+    for index in order.into_iter().take((total - allocated) as usize) {
+        amounts[index] += 1;
+    }
+    Ok(amounts)
+}
+
+/// Evaluates an allocation function at distance `distance` from the active
+/// bin; `max_distance` is the farthest bin on the same side. Parameters and
+/// the result are scaled by PARAM_SCALE. The SDK mirrors this in
+/// `origin_dex_sdk.program_weights`; both are pinned by
+/// `tests/golden/allocation_functions.txt`.
+fn function_weight(
+    function_type: u8,
+    params: &[i64; 5],
+    distance: u32,
+    max_distance: u32,
+) -> Result<i128> {
+    if function_type == FUNCTION_LINEAR {
+        // f(x) = m(x - x0) + y0
+        let scale = PARAM_SCALE as i128;
+        let x = distance as i128 * scale;
+        let [m, x0, y0, _, _] = params.map(|param| param as i128);
+        let weight = m.checked_mul(x - x0).ok_or(DexError::FunctionDomainError)? / scale + y0;
+        return Ok(weight);
+    }
+
+    let [p0, p1, p2, p3, p4] = params.map(math::from_param);
+    let d = distance as i128 * math::ONE;
+    let weight = match function_type {
+        FUNCTION_LOG => {
+            // g(x) = A * log_B(C(-x + h)) + k
+            let arg = math::mul(p2, p3 - d).ok_or(DexError::FunctionDomainError)?;
+            let log = math::log_b(arg, p1).ok_or(DexError::FunctionDomainError)?;
+            math::mul(p0, log).ok_or(DexError::FunctionDomainError)? + p4
+        }
+        FUNCTION_SPOT => math::ONE,
+        FUNCTION_CURVE => gaussian(d, p0)?,
+        FUNCTION_BIDASK => {
+            let edge = math::ONE - gaussian(d, p0)?;
+            math::ONE + math::mul(p1, edge).ok_or(DexError::FunctionDomainError)?
+        }
+        FUNCTION_EXPONENTIAL => math::pow(p0, d).ok_or(DexError::FunctionDomainError)?,
+        FUNCTION_POWER => {
+            let denominator = math::pow(d + p1, p0).ok_or(DexError::FunctionDomainError)?;
+            math::div(math::ONE, denominator).ok_or(DexError::FunctionDomainError)?
+        }
+        FUNCTION_WALL_DECAY => {
+            let wall_bins = params[0] / PARAM_SCALE;
+            if (distance as i64) <= wall_bins {
+                math::ONE
+            } else {
+                let decay = (distance as i128 - wall_bins as i128) * math::ONE;
+                math::pow(p1, decay).ok_or(DexError::FunctionDomainError)?
+            }
+        }
+        FUNCTION_PRESET_POWER
+        | FUNCTION_PRESET_EXP
+        | FUNCTION_PRESET_LOG
+        | FUNCTION_PRESET_SIGMOID => {
+            let x = if max_distance > 1 {
+                (distance as i128 - 1) * math::ONE / (max_distance as i128 - 1)
+            } else {
+                0
+            };
+            let curve = preset_curve(function_type, p2, p3, x)?;
+            p0 + math::mul(p1, curve).ok_or(DexError::FunctionDomainError)?
+        }
+        _ => return err!(DexError::InvalidFunctionSpec),
+    };
+    Ok(math::to_param(weight).ok_or(DexError::FunctionDomainError)? as i128)
+}
+
+/// Normalized preset curve f(x) in [0, 1] at ONE scale.
+fn preset_curve(function_type: u8, shape: i128, midpoint: i128, x: i128) -> Result<i128> {
+    let curve = match function_type {
+        FUNCTION_PRESET_POWER => math::pow(x, shape),
+        FUNCTION_PRESET_EXP => {
+            let numerator = math::exp(math::mul(shape, x).ok_or(DexError::FunctionDomainError)?);
+            let denominator = math::exp(shape);
+            numerator
+                .zip(denominator)
+                .and_then(|(n, d)| math::div(n - math::ONE, d - math::ONE))
+        }
+        FUNCTION_PRESET_LOG => {
+            let numerator =
+                math::ln(math::ONE + math::mul(shape, x).ok_or(DexError::FunctionDomainError)?);
+            let denominator = math::ln(math::ONE + shape);
+            numerator
+                .zip(denominator)
+                .and_then(|(n, d)| math::div(n, d))
+        }
+        FUNCTION_PRESET_SIGMOID => {
+            let logistic = |t: i128| {
+                let exponent = math::mul(shape, midpoint - t)?;
+                math::div(math::ONE, math::ONE + math::exp(exponent)?)
+            };
+            let low = logistic(0);
+            let high = logistic(math::ONE);
+            logistic(x)
+                .zip(low.zip(high))
+                .and_then(|(value, (low, high))| math::div(value - low, high - low))
+        }
+        _ => None,
+    };
+    curve.ok_or(error!(DexError::FunctionDomainError))
+}
+
+/// exp(-(d - 1)^2 / (2 * sigma^2)) at ONE scale.
+fn gaussian(d: i128, sigma: i128) -> Result<i128> {
+    let offset = d - math::ONE;
+    let numerator = math::mul(offset, offset).ok_or(DexError::FunctionDomainError)?;
+    let denominator = 2 * math::mul(sigma, sigma).ok_or(DexError::FunctionDomainError)?;
+    let exponent = math::div(numerator, denominator).ok_or(DexError::FunctionDomainError)?;
+    math::exp(-exponent).ok_or(error!(DexError::FunctionDomainError))
+}
+
+/// Token B is placed left of the active bin and token A right of it; the
+/// active bin itself never takes deposits. Both sides are ordered by
+/// increasing distance from the active bin.
+fn side_bins(active_bin_id: i32, position: &Position) -> (Vec<i32>, Vec<i32>) {
+    let left_bins = (position.lower_bin_id..=position.upper_bin_id)
+        .rev()
+        .filter(|bin_id| *bin_id < active_bin_id)
+        .collect();
+    let right_bins = (position.lower_bin_id..=position.upper_bin_id)
+        .filter(|bin_id| *bin_id > active_bin_id)
+        .collect();
+    (left_bins, right_bins)
+}
+
+fn bin_distances(active_bin_id: i32, bins: &[i32]) -> Vec<u32> {
+    bins.iter()
+        .map(|bin_id| active_bin_id.abs_diff(*bin_id))
+        .collect()
+}
+
+/// Checks both side functions over the bins the position currently covers.
+fn validate_position_functions(active_bin_id: i32, position: &Position) -> Result<()> {
+    let (left_bins, right_bins) = side_bins(active_bin_id, position);
+    function_weights(
+        position.left_function_type,
+        &position.left_params,
+        &bin_distances(active_bin_id, &left_bins),
+    )?;
+    function_weights(
+        position.right_function_type,
+        &position.right_params,
+        &bin_distances(active_bin_id, &right_bins),
+    )?;
+    Ok(())
+}
+
+fn deposit_to_bins<B: DerefMut<Target = BinArray>>(
+    pool: &Pool,
+    position: &mut Position,
+    bin_arrays: &mut [B],
+    amount_a: u64,
+    amount_b: u64,
+) -> Result<()> {
+    let (left_bins, right_bins) = side_bins(pool.active_bin_id, position);
+    let left_distances = bin_distances(pool.active_bin_id, &left_bins);
+    let right_distances = bin_distances(pool.active_bin_id, &right_bins);
+
+    // Each side holds a single token, so each side is normalized on its own.
+    let left_amounts = allocate_side(
+        amount_b,
+        position.left_function_type,
+        &position.left_params,
+        &left_distances,
+    )?;
+    let right_amounts = allocate_side(
+        amount_a,
+        position.right_function_type,
+        &position.right_params,
+        &right_distances,
+    )?;
+
+    for (bin_id, amount) in left_bins.iter().zip(left_amounts) {
+        add_to_bin(pool, position, bin_arrays, *bin_id, 0, amount)?;
+    }
+    for (bin_id, amount) in right_bins.iter().zip(right_amounts) {
+        add_to_bin(pool, position, bin_arrays, *bin_id, amount, 0)?;
+    }
+    Ok(())
+}
+
+fn add_to_bin<B: DerefMut<Target = BinArray>>(
+    pool: &Pool,
+    position: &mut Position,
+    bin_arrays: &mut [B],
+    bin_id: i32,
+    amount_a: u64,
+    amount_b: u64,
+) -> Result<()> {
+    if amount_a == 0 && amount_b == 0 {
+        return Ok(());
+    }
+    let bin = find_bin(bin_arrays, bin_id)?;
+    position.accrue_fees(bin_id, bin)?;
+    let deposit_value = bin_value(pool, bin_id, amount_a, amount_b)?;
+    let current_value = bin_value(pool, bin_id, bin.amount_a, bin.amount_b)?;
+    let shares = if bin.liquidity_supply == 0 || current_value == 0 {
+        deposit_value
+    } else {
+        deposit_value
+            .checked_mul(bin.liquidity_supply)
+            .ok_or(DexError::Overflow)?
+            / current_value
+    };
+
+    bin.amount_a = bin
+        .amount_a
+        .checked_add(amount_a)
+        .ok_or(DexError::Overflow)?;
+    bin.amount_b = bin
+        .amount_b
+        .checked_add(amount_b)
+        .ok_or(DexError::Overflow)?;
+    bin.liquidity_supply = bin
+        .liquidity_supply
+        .checked_add(shares)
+        .ok_or(DexError::Overflow)?;
+
+    let index = position.share_index(bin_id)?;
+    position.liquidity_shares[index] = position.liquidity_shares[index]
+        .checked_add(shares)
+        .ok_or(DexError::Overflow)?;
+    Ok(())
+}
+
+/// Withdraws `bps` of a position from its bins and returns the released token
+/// amounts. Pool totals and the position's recorded deposits shrink by the
+/// same fraction; rounding favours the bins.
+fn withdraw_position<B: DerefMut<Target = BinArray>>(
+    pool: &mut Pool,
+    position: &mut Position,
+    bin_arrays: &mut [B],
+    bps: u16,
+) -> Result<(u64, u64)> {
+    validate_position_bins(position.lower_bin_id, position.upper_bin_id, bin_arrays)?;
+
+    let mut amount_a: u64 = 0;
+    let mut amount_b: u64 = 0;
+    for bin_id in position.lower_bin_id..=position.upper_bin_id {
+        let index = position.share_index(bin_id)?;
+        let shares = bps_of(position.liquidity_shares[index], bps)?;
+        if shares == 0 {
+            continue;
+        }
+        let bin = find_bin(bin_arrays, bin_id)?;
+        position.accrue_fees(bin_id, bin)?;
+        let out_a = share_of(bin.amount_a, shares, bin.liquidity_supply)?;
+        let out_b = share_of(bin.amount_b, shares, bin.liquidity_supply)?;
+        bin.amount_a -= out_a;
+        bin.amount_b -= out_b;
+        bin.liquidity_supply -= shares;
+        position.liquidity_shares[index] -= shares;
+
+        amount_a = amount_a.checked_add(out_a).ok_or(DexError::Overflow)?;
+        amount_b = amount_b.checked_add(out_b).ok_or(DexError::Overflow)?;
+    }
+
+    pool.total_a_amount = pool
+        .total_a_amount
+        .checked_sub(amount_a)
+        .ok_or(DexError::Overflow)?;
+    pool.total_b_amount = pool
+        .total_b_amount
+        .checked_sub(amount_b)
+        .ok_or(DexError::Overflow)?;
+    position.amount_a -= bps_of(position.amount_a as u128, bps)? as u64;
+    position.amount_b -= bps_of(position.amount_b as u128, bps)? as u64;
+    Ok((amount_a, amount_b))
+}
+
+fn bps_of(value: u128, bps: u16) -> Result<u128> {
+    Ok(value.checked_mul(bps as u128).ok_or(DexError::Overflow)? / BPS_DENOMINATOR as u128)
+}
+
+/// Token amount owed to `shares` out of a bin's `supply`, rounded down.
+fn share_of(amount: u64, shares: u128, supply: u128) -> Result<u64> {
+    let owed = (amount as u128)
+        .checked_mul(shares)
+        .ok_or(DexError::Overflow)?
+        / supply;
+    Ok(owed as u64)
+}
+
+fn execute_swap<'info>(
+    accounts: &mut Swap<'info>,
+    remaining_accounts: &'info [AccountInfo<'info>],
+    mode: SwapMode,
+    swap_for_b: bool,
+) -> Result<SwapResult> {
+    check_no_flash_loan(&accounts.pool)?;
+    let pool_key = accounts.pool.key();
+    let bin_array_loaders = load_bin_arrays(&pool_key, remaining_accounts)?;
+    let mut bin_arrays = bin_array_loaders
+        .iter()
+        .map(|bin_array| bin_array.load_mut())
+        .collect::<Result<Vec<_>>>()?;
+    let slot = Clock::get()?.slot;
+    let active_price = bin_price(&accounts.pool, accounts.pool.active_bin_id)?;
+    accounts.observations.record(slot, active_price);
+    let result = swap_through_bins(&mut accounts.pool, &mut bin_arrays, mode, swap_for_b, slot)?;
+
+    let (user_in, vault_in, vault_out, user_out) = if swap_for_b {
+        (
+            &accounts.user_token_a,
+            &accounts.vault_a,
+            &accounts.vault_b,
+            &accounts.user_token_b,
+        )
+    } else {
+        (
+            &accounts.user_token_b,
+            &accounts.vault_b,
+            &accounts.vault_a,
+            &accounts.user_token_a,
+        )
+    };
+    transfer_to_vault(
+        &accounts.token_program,
+        &accounts.user,
+        user_in,
+        vault_in,
+        result.amount_in,
+    )?;
+    transfer_from_vault(
+        &accounts.token_program,
+        &accounts.pool,
+        vault_out,
+        user_out,
+        result.amount_out,
+    )?;
+
+    Ok(result)
+}
+
+/// Runs each hop of a `swap_route` as an exact-in swap. Hop inputs move
+/// straight from the previous pool's output vault, so intermediate tokens
+/// never touch the user's accounts.
+fn execute_route<'info>(
+    accounts: &SwapRoute<'info>,
+    remaining_accounts: &'info [AccountInfo<'info>],
+    amount_in: u64,
+    bin_array_counts: &[u8],
+) -> Result<u64> {
+    if bin_array_counts.is_empty() || bin_array_counts.len() > MAX_ROUTE_HOPS {
+        return err!(DexError::InvalidRoute);
+    }
+    let slot = Clock::get()?.slot;
+    let mut mint = accounts.user_token_in.mint;
+    let mut amount = amount_in;
+    let mut pools: Vec<Pubkey> = Vec::with_capacity(bin_array_counts.len());
+    let mut previous: Option<(Account<'info, Pool>, Account<'info, TokenAccount>)> = None;
+    let mut hop_accounts = remaining_accounts;
+
+    for &bin_array_count in bin_array_counts {
+        let len = 4 + bin_array_count as usize;
+        if hop_accounts.len() < len {
+            return err!(DexError::InvalidRoute);
+        }
+        let (hop, rest) = hop_accounts.split_at(len);
+        hop_accounts = rest;
+
+        if !hop[0].is_writable {
+            return err!(DexError::InvalidRoute);
+        }
+        let mut pool = Account::<Pool>::try_from(&hop[0])?;
+        // A pool visited twice would be written back from a stale copy.
+        if pools.contains(&pool.key()) {
+            return err!(DexError::InvalidRoute);
+        }
+        pools.push(pool.key());
+        check_no_flash_loan(&pool)?;
+
+        let (swap_for_b, mint_out) = route_direction(pool.token_a_mint, pool.token_b_mint, mint)?;
+        let (vault_in_key, vault_out_key) = if swap_for_b {
+            (pool.vault_a, pool.vault_b)
+        } else {
+            (pool.vault_b, pool.vault_a)
+        };
+        let vault_in = Account::<TokenAccount>::try_from(&hop[2])?;
+        let vault_out = Account::<TokenAccount>::try_from(&hop[3])?;
+        require_keys_eq!(vault_in.key(), vault_in_key, DexError::InvalidRoute);
+        require_keys_eq!(vault_out.key(), vault_out_key, DexError::InvalidRoute);
+
+        if !hop[1].is_writable {
+            return err!(DexError::InvalidRoute);
+        }
+        let mut observations = Account::<Observations>::try_from(&hop[1])?;
+        require_keys_eq!(observations.pool, pool.key(), DexError::InvalidRoute);
+        observations.record(slot, bin_price(&pool, pool.active_bin_id)?);
+        observations.exit(&crate::ID)?;
+
+        let pool_key = pool.key();
+        let bin_array_loaders = load_bin_arrays(&pool_key, &hop[4..])?;
+        let mut bin_arrays = bin_array_loaders
+            .iter()
+            .map(|bin_array| bin_array.load_mut())
+            .collect::<Result<Vec<_>>>()?;
+        let result = swap_through_bins(
+            &mut pool,
+            &mut bin_arrays,
+            SwapMode::ExactIn(amount),
+            swap_for_b,
+            slot,
+        )?;
+
+        match previous.take() {
+            None => transfer_to_vault(
+                &accounts.token_program,
+                &accounts.user,
+                &accounts.user_token_in,
+                &vault_in,
+                result.amount_in,
+            )?,
+            Some((previous_pool, previous_vault_out)) => {
+                transfer_from_vault(
+                    &accounts.token_program,
+                    &previous_pool,
+                    &previous_vault_out,
+                    &vault_in,
+                    result.amount_in,
+                )?;
+                previous_pool.exit(&crate::ID)?;
+            }
+        }
+
+        if result.amount_out == 0 {
+            return err!(DexError::InvalidAmount);
+        }
+        mint = mint_out;
+        amount = result.amount_out;
+        previous = Some((pool, vault_out));
+    }
+
+    if !hop_accounts.is_empty() || mint != accounts.user_token_out.mint {
+        return err!(DexError::InvalidRoute);
+    }
+    let (last_pool, last_vault_out) = previous.ok_or(DexError::InvalidRoute)?;
+    transfer_from_vault(
+        &accounts.token_program,
+        &last_pool,
+        &last_vault_out,
+        &accounts.user_token_out,
+        amount,
+    )?;
+    last_pool.exit(&crate::ID)?;
+    Ok(amount)
+}
+
+/// Direction of a route hop entering a pool with `mint_in`, and the mint it
+/// leaves with.
+fn route_direction(
+    token_a_mint: Pubkey,
+    token_b_mint: Pubkey,
+    mint_in: Pubkey,
+) -> Result<(bool, Pubkey)> {
+    if mint_in == token_a_mint {
+        Ok((true, token_b_mint))
+    } else if mint_in == token_b_mint {
+        Ok((false, token_a_mint))
+    } else {
+        err!(DexError::InvalidRoute)
+    }
+}
+
+fn load_bin_arrays<'info>(
+    pool: &Pubkey,
+    accounts: &'info [AccountInfo<'info>],
+) -> Result<Vec<AccountLoader<'info, BinArray>>> {
+    // A bin array passed twice would be borrowed twice; reject it like a
+    // pool repeated in a route.
+    for (index, info) in accounts.iter().enumerate() {
+        if accounts[..index].iter().any(|other| other.key == info.key) {
+            return err!(DexError::InvalidBinArray);
+        }
+    }
+    accounts
+        .iter()
+        .map(|info| {
+            if !info.is_writable {
+                return err!(DexError::InvalidBinArray);
+            }
+            let bin_array = AccountLoader::<BinArray>::try_from(info)?;
+            require_keys_eq!(bin_array.load()?.pool, *pool, DexError::InvalidBinArray);
+            Ok(bin_array)
+        })
+        .collect()
+}
+
+fn find_bin<B: DerefMut<Target = BinArray>>(bin_arrays: &mut [B], bin_id: i32) -> Result<&mut Bin> {
+    let bin_array = bin_arrays
+        .iter_mut()
+        .find(|bin_array| bin_array.contains(bin_id))
+        .ok_or(DexError::InvalidBinArray)?;
+    bin_array.bin_mut(bin_id)
+}
+
+/// Amount of the output token received for `amount_in` at the price of `bin_id`.
+fn quote_out(pool: &Pool, bin_id: i32, amount_in: u64, swap_for_b: bool) -> Result<u64> {
+    let price = bin_price(pool, bin_id)?;
+    let amount_out = if swap_for_b {
+        (amount_in as u128)
+            .checked_mul(price)
+            .ok_or(DexError::Overflow)?
+            >> 64
+    } else {
+        ((amount_in as u128) << 64) / price
+    };
+    u64::try_from(amount_out).map_err(|_| error!(DexError::Overflow))
+}
+
+/// Input needed to receive `amount_out` at the price of `bin_id`, rounded up.
+fn quote_in(pool: &Pool, bin_id: i32, amount_out: u64, swap_for_b: bool) -> Result<u64> {
+    let price = bin_price(pool, bin_id)?;
+    let amount_in = if swap_for_b {
+        ((amount_out as u128) << 64).div_ceil(price)
+    } else {
+        (amount_out as u128)
+            .checked_mul(price)
+            .ok_or(DexError::Overflow)?
+            .div_ceil(Q64)
+    };
+    u64::try_from(amount_in).map_err(|_| error!(DexError::Overflow))
+}
+
+/// Fee charged on a gross input amount, rounded up.
+fn fee_on_amount(amount: u64, fee_bps: u16) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(DexError::Overflow)?
+        .div_ceil(BPS_DENOMINATOR as u128);
+    u64::try_from(fee).map_err(|_| error!(DexError::Overflow))
+}
+
+/// Gross input whose fee-exclusive part covers `amount`, rounded up.
+fn amount_with_fee(amount: u64, fee_bps: u16) -> Result<u64> {
+    if fee_bps as u64 >= BPS_DENOMINATOR {
+        return err!(DexError::InvalidFee);
+    }
+    let gross = (amount as u128)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(DexError::Overflow)?
+        .div_ceil((BPS_DENOMINATOR - fee_bps as u64) as u128);
+    u64::try_from(gross).map_err(|_| error!(DexError::Overflow))
+}
+
+/// Fee growth per share for `fee` spread over `supply` shares, Q64.64.
+fn fee_growth_delta(fee: u64, supply: u128) -> u128 {
+    ((fee as u128) << 64) / supply
+}
+
+/// Fees earned by `shares` since `checkpoint`, rounded down. Growth wraps, so
+/// the difference is taken modulo 2^128.
+fn pending_fee(shares: u128, growth: u128, checkpoint: u128) -> Result<u64> {
+    let owed = mul_shr_64(shares, growth.wrapping_sub(checkpoint)).ok_or(DexError::Overflow)?;
+    u64::try_from(owed).map_err(|_| error!(DexError::Overflow))
+}
+
+/// (a * b) >> 64 without a 256-bit intermediate.
+fn mul_shr_64(a: u128, b: u128) -> Option<u128> {
+    let (a_hi, a_lo) = (a >> 64, a & u64::MAX as u128);
+    let (b_hi, b_lo) = (b >> 64, b & u64::MAX as u128);
+    let high = (a_hi * b_hi).checked_mul(1 << 64)?;
+    high.checked_add(a_hi * b_lo)?
+        .checked_add(a_lo * b_hi)?
+        .checked_add((a_lo * b_lo) >> 64)
+}
+
+#[derive(Clone, Copy)]
+enum SwapMode {
+    ExactIn(u64),
+    ExactOut(u64),
+}
+
+struct SwapResult {
+    amount_in: u64,
+    amount_out: u64,
+}
+
+fn swap_through_bins<B: DerefMut<Target = BinArray>>(
+    pool: &mut Pool,
+    bin_arrays: &mut [B],
+    mode: SwapMode,
+    swap_for_b: bool,
+    slot: u64,
+) -> Result<SwapResult> {
+    let protocol_fee_share_bps = pool.protocol_fee_share_bps as u128;
+    pool.dynamic_fee.update_references(pool.active_bin_id, slot);
+    let mut remaining = match mode {
+        SwapMode::ExactIn(amount) | SwapMode::ExactOut(amount) => amount,
+    };
+    let mut amount_in: u64 = 0;
+    let mut amount_out: u64 = 0;
+    let mut fees: u64 = 0;
+    let mut house_fees: u64 = 0;
+    let mut orders_in: u64 = 0;
+    let mut orders_out: u64 = 0;
+    let mut bin_id = pool.active_bin_id;
+    let mut bins_crossed = 0;
+
+    // Selling A walks down through the bins holding B, selling B walks up
+    // through the bins holding A, starting at the active bin.
+    loop {
+        bins_crossed += 1;
+        if bins_crossed > MAX_BINS_PER_SWAP {
+            return err!(DexError::SwapBinLimitExceeded);
+        }
+
+        // Limit orders selling the output token fill pro rata with the LPs.
+        let (reserve_out, order_reserve, has_lps) = {
+            let bin = find_bin(bin_arrays, bin_id)?;
+            let (order_reserve, _, _) = bin.orders(!swap_for_b);
+            let lp_reserve = if swap_for_b {
+                bin.amount_b
+            } else {
+                bin.amount_a
+            };
+            (
+                lp_reserve
+                    .checked_add(order_reserve)
+                    .ok_or(DexError::Overflow)?,
+                order_reserve,
+                bin.liquidity_supply > 0,
+            )
+        };
+
+        // Each bin is charged the base fee plus the variable fee for the
+        // volatility accumulated up to it. Makers earn their limit price, not
+        // fees, so a bin without LPs charges only the house share.
+        pool.dynamic_fee.update_volatility_accumulator(bin_id);
+        let fee_bps = pool.dynamic_fee.total_fee_bps(pool.fee_bps);
+        let fee_bps = if has_lps {
+            fee_bps
+        } else {
+            (fee_bps as u128 * protocol_fee_share_bps / BPS_DENOMINATOR as u128) as u16
+        };
+
+        if reserve_out > 0 {
+            let max_in_net = quote_in(pool, bin_id, reserve_out, swap_for_b)?;
+            let max_in = amount_with_fee(max_in_net, fee_bps)?;
+            let (bin_in, bin_out, fee) = match mode {
+                SwapMode::ExactIn(_) if remaining >= max_in => {
+                    (max_in, reserve_out, max_in - max_in_net)
+                }
+                SwapMode::ExactIn(_) => {
+                    let fee = fee_on_amount(remaining, fee_bps)?;
+                    let out = quote_out(pool, bin_id, remaining - fee, swap_for_b)?;
+                    (remaining, out.min(reserve_out), fee)
+                }
+                SwapMode::ExactOut(_) if remaining >= reserve_out => {
+                    (max_in, reserve_out, max_in - max_in_net)
+                }
+                SwapMode::ExactOut(_) => {
+                    let net = quote_in(pool, bin_id, remaining, swap_for_b)?;
+                    let gross = amount_with_fee(net, fee_bps)?;
+                    (gross, remaining, gross - net)
+                }
+            };
+
+            // The orders' part of the output is paid for at the bin price
+            // (rounded up, but never past the fee-exclusive input) and leaves
+            // the bin; the rest of the fee-exclusive input becomes LP
+            // reserves.
+            let net_in = bin_in.checked_sub(fee).ok_or(DexError::Overflow)?;
+            let order_out = (bin_out as u128 * order_reserve as u128 / reserve_out as u128) as u64;
+            let order_in = quote_in(pool, bin_id, order_out, swap_for_b)?.min(net_in);
+            let lp_in = net_in - order_in;
+            let lp_out = bin_out - order_out;
+
+            // The LP share of the fee accrues to the bin's shares; the house
+            // share (rounded down) is held in the vault until
+            // `withdraw_house_fees`. Without LPs the fee was already cut to
+            // the house share above.
+            let bin = find_bin(bin_arrays, bin_id)?;
+            let house_fee = if has_lps {
+                (fee as u128 * protocol_fee_share_bps / BPS_DENOMINATOR as u128) as u64
+            } else {
+                fee
+            };
+            if swap_for_b {
+                bin.amount_a = bin.amount_a.checked_add(lp_in).ok_or(DexError::Overflow)?;
+                bin.amount_b = bin.amount_b.checked_sub(lp_out).ok_or(DexError::Overflow)?;
+            } else {
+                bin.amount_b = bin.amount_b.checked_add(lp_in).ok_or(DexError::Overflow)?;
+                bin.amount_a = bin.amount_a.checked_sub(lp_out).ok_or(DexError::Overflow)?;
             }
+            bin.fill_orders(!swap_for_b, order_out, order_in)?;
+            bin.accrue_fee(fee - house_fee, swap_for_b);
+            orders_in = orders_in.checked_add(order_in).ok_or(DexError::Overflow)?;
+            orders_out = orders_out
+                .checked_add(order_out)
+                .ok_or(DexError::Overflow)?;
+
+            fees = fees.checked_add(fee).ok_or(DexError::Overflow)?;
+            house_fees = house_fees
+                .checked_add(house_fee)
+                .ok_or(DexError::Overflow)?;
+            amount_in = amount_in.checked_add(bin_in).ok_or(DexError::Overflow)?;
+            amount_out = amount_out.checked_add(bin_out).ok_or(DexError::Overflow)?;
+            remaining -= match mode {
+                SwapMode::ExactIn(_) => bin_in,
+                SwapMode::ExactOut(_) => bin_out,
+            };
+        }
+
+        if remaining == 0 {
+            break;
+        }
+        bin_id = if swap_for_b {
+            bin_id.checked_sub(1)
+        } else {
+            bin_id.checked_add(1)
+        }
+        .ok_or(DexError::Overflow)?;
+    }
+
+    pool.active_bin_id = bin_id;
+    // Pool totals count LP reserves only.
+    let net_in = amount_in
+        .checked_sub(fees)
+        .and_then(|amount| amount.checked_sub(orders_in))
+        .ok_or(DexError::Overflow)?;
+    let lp_out = amount_out
+        .checked_sub(orders_out)
+        .ok_or(DexError::Overflow)?;
+    if swap_for_b {
+        pool.house_fees_a = pool
+            .house_fees_a
+            .checked_add(house_fees)
+            .ok_or(DexError::Overflow)?;
+        pool.total_a_amount = pool
+            .total_a_amount
+            .checked_add(net_in)
+            .ok_or(DexError::Overflow)?;
+        pool.total_b_amount = pool
+            .total_b_amount
+            .checked_sub(lp_out)
+            .ok_or(DexError::Overflow)?;
+    } else {
+        pool.house_fees_b = pool
+            .house_fees_b
+            .checked_add(house_fees)
+            .ok_or(DexError::Overflow)?;
+        pool.total_b_amount = pool
+            .total_b_amount
+            .checked_add(net_in)
+            .ok_or(DexError::Overflow)?;
+        pool.total_a_amount = pool
+            .total_a_amount
+            .checked_sub(lp_out)
+            .ok_or(DexError::Overflow)?;
+    }
+
+    Ok(SwapResult {
+        amount_in,
+        amount_out,
+    })
+}
+
+fn transfer_deposit<'info>(
+    token_program: &Program<'info, Token>,
+    owner: &Signer<'info>,
+    owner_token_a: &Account<'info, TokenAccount>,
+    owner_token_b: &Account<'info, TokenAccount>,
+    vault_a: &Account<'info, TokenAccount>,
+    vault_b: &Account<'info, TokenAccount>,
+    amount_a: u64,
+    amount_b: u64,
+) -> Result<()> {
+    transfer_to_vault(token_program, owner, owner_token_a, vault_a, amount_a)?;
+    transfer_to_vault(token_program, owner, owner_token_b, vault_b, amount_b)?;
+    Ok(())
+}
+
+fn transfer_to_vault<'info>(
+    token_program: &Program<'info, Token>,
+    authority: &Signer<'info>,
+    from: &Account<'info, TokenAccount>,
+    vault: &Account<'info, TokenAccount>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    token::transfer(
+        CpiContext::new(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: from.to_account_info(),
+                to: vault.to_account_info(),
+                authority: authority.to_account_info(),
+            },
+        ),
+        amount,
+    )
+}
+
+fn transfer_from_vault<'info>(
+    token_program: &Program<'info, Token>,
+    pool: &Account<'info, Pool>,
+    vault: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: vault.to_account_info(),
+                to: to.to_account_info(),
+                authority: pool.to_account_info(),
+            },
+            &[&[b"pool", &pool.pool_id.to_le_bytes(), &[pool.bump]]],
+        ),
+        amount,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pool with zeroed fields and a 100 bps bin step, so bin 0 prices A
+    /// at 1 B.
+    fn test_pool() -> Pool {
+        let mut data = Pool::DISCRIMINATOR.to_vec();
+        data.resize(8 + Pool::SIZE, 0);
+        let mut pool = Pool::try_deserialize(&mut data.as_slice()).unwrap();
+        pool.bin_step_bps = 100;
+        pool
+    }
+
+    fn empty_bin_array(index: i64) -> BinArray {
+        BinArray {
+            pool: Pubkey::default(),
+            index,
+            bump: 0,
+            _padding: [0; 7],
+            bins: [Bin::default(); BIN_ARRAY_SIZE],
+        }
+    }
+
+    /// Bin array 0 with 100 LP tokens of A in each of `bins`, above the
+    /// active bin 0 of `pool`.
+    fn bins_holding_a(pool: &mut Pool, bins: &[usize]) -> BinArray {
+        let mut bin_array = empty_bin_array(0);
+        for &bin in bins {
+            bin_array.bins[bin].amount_a = 100;
+            bin_array.bins[bin].liquidity_supply = 100;
+            pool.total_a_amount += 100;
         }
-        // 3 = Wrapped SOL (no freeze requirement)
-        3 => {}
-        // 4 = USDC (frozen required)
-        4 => {
-            if mint.freeze_authority.is_none() {
-                msg!("{} mint must be frozen for USDC", label);
-                return err!(DexError::MintNotFrozen);
-            }
+        bin_array
+    }
+
+    #[test]
+    fn withdrawals_release_a_share_of_each_bin() {
+        let mut pool = test_pool();
+        (pool.total_a_amount, pool.total_b_amount) = (130, 50);
+        let (mut lower, mut upper) = (empty_bin_array(0), empty_bin_array(1));
+        lower.bins[BIN_ARRAY_SIZE - 1] = Bin {
+            amount_a: 100,
+            amount_b: 50,
+            liquidity_supply: 200,
+            ..Bin::default()
+        };
+        upper.bins[0] = Bin {
+            amount_a: 30,
+            liquidity_supply: 100,
+            ..Bin::default()
+        };
+        lower.bins[BIN_ARRAY_SIZE - 1].accrue_fee(200, true);
+
+        let mut data = Position::DISCRIMINATOR.to_vec();
+        data.resize(8 + Position::SIZE, 0);
+        let mut position = Box::new(Position::try_deserialize(&mut data.as_slice()).unwrap());
+        position.lower_bin_id = BIN_ARRAY_SIZE as i32 - 1;
+        position.upper_bin_id = BIN_ARRAY_SIZE as i32;
+        position.liquidity_shares[..2].copy_from_slice(&[100, 100]);
+        (position.amount_a, position.amount_b) = (80, 25);
+
+        // Half of the position's shares take half of its part of each bin,
+        // rounded down, and its fees are settled before the shares change.
+        let withdrawn = withdraw_position(
+            &mut pool,
+            &mut position,
+            &mut [&mut lower, &mut upper],
+            5_000,
+        );
+        assert_eq!(withdrawn, Ok((40, 12)));
+        assert_eq!(position.fees_owed_a, 100);
+        assert_eq!(position.liquidity_shares[..2], [50, 50]);
+        assert_eq!((position.amount_a, position.amount_b), (40, 13));
+        assert_eq!((pool.total_a_amount, pool.total_b_amount), (90, 38));
+        let bin = &lower.bins[BIN_ARRAY_SIZE - 1];
+        assert_eq!(
+            (bin.amount_a, bin.amount_b, bin.liquidity_supply),
+            (75, 38, 150)
+        );
+        assert_eq!(
+            (upper.bins[0].amount_a, upper.bins[0].liquidity_supply),
+            (15, 50)
+        );
+
+        // The rest empties the position; other LPs keep their part.
+        let withdrawn = withdraw_position(
+            &mut pool,
+            &mut position,
+            &mut [&mut lower, &mut upper],
+            10_000,
+        );
+        assert_eq!(withdrawn, Ok((40, 12)));
+        assert_eq!(position.liquidity_shares[..2], [0, 0]);
+        assert_eq!((position.amount_a, position.amount_b), (0, 0));
+        let bin = &lower.bins[BIN_ARRAY_SIZE - 1];
+        assert_eq!(
+            (bin.amount_a, bin.amount_b, bin.liquidity_supply),
+            (50, 26, 100)
+        );
+        assert_eq!(upper.bins[0].liquidity_supply, 0);
+
+        // Bin arrays that do not cover the position are rejected.
+        assert_eq!(
+            withdraw_position(
+                &mut pool,
+                &mut position,
+                &mut [&mut upper, &mut lower],
+                10_000
+            ),
+            err!(DexError::InvalidBinArray)
+        );
+    }
+
+    #[test]
+    fn positions_inside_one_bin_array_use_it_alone() {
+        let (lower, upper) = (empty_bin_array(0), empty_bin_array(1));
+        let last = BIN_ARRAY_SIZE as i32 - 1;
+        assert_eq!(validate_position_bins(6, last, &[&lower]), Ok(()));
+        assert_eq!(validate_position_bins(3, 3, &[&lower]), Ok(()));
+        assert_eq!(validate_position_bins(6, last, &[&lower, &upper]), Ok(()));
+
+        // A range that reaches the next array needs both arrays.
+        assert_eq!(
+            validate_position_bins(last, last + 1, &[&lower]),
+            err!(DexError::InvalidBinArray)
+        );
+        assert_eq!(
+            validate_position_bins(last, last + 1, &[&upper]),
+            err!(DexError::InvalidBinArray)
+        );
+        assert_eq!(
+            validate_position_bins(0, 1, &[&lower, &lower]),
+            err!(DexError::InvalidBinArray)
+        );
+    }
+
+    #[test]
+    fn exact_in_swaps_cross_bins_up_to_the_limit() {
+        // 150 B empties bin 0 at 1 and buys 49 of bin 1 at 1.01 with the
+        // remaining 50, leaving bin 1 active and partly swapped.
+        let mut pool = test_pool();
+        let mut bin_array = bins_holding_a(&mut pool, &[0, 1, 2]);
+        let result = swap_through_bins(
+            &mut pool,
+            &mut [&mut bin_array],
+            SwapMode::ExactIn(150),
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!((result.amount_in, result.amount_out), (150, 149));
+        assert_eq!(pool.active_bin_id, 1);
+        assert_eq!((pool.total_a_amount, pool.total_b_amount), (151, 150));
+        let bins = &bin_array.bins;
+        assert_eq!((bins[0].amount_a, bins[0].amount_b), (0, 100));
+        assert_eq!((bins[1].amount_a, bins[1].amount_b), (51, 50));
+        assert_eq!((bins[2].amount_a, bins[2].amount_b), (100, 0));
+
+        // Empty bins count towards the limit: the last bin a swap may reach
+        // is MAX_BINS_PER_SWAP - 1 bins away from the active one.
+        let mut pool = test_pool();
+        let mut bin_array = bins_holding_a(&mut pool, &[MAX_BINS_PER_SWAP - 1]);
+        swap_through_bins(
+            &mut pool,
+            &mut [&mut bin_array],
+            SwapMode::ExactIn(10),
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!(pool.active_bin_id, MAX_BINS_PER_SWAP as i32 - 1);
+
+        let mut pool = test_pool();
+        let mut bin_array = bins_holding_a(&mut pool, &[MAX_BINS_PER_SWAP]);
+        assert_eq!(
+            swap_through_bins(
+                &mut pool,
+                &mut [&mut bin_array],
+                SwapMode::ExactIn(10),
+                false,
+                0,
+            )
+            .err(),
+            Some(error!(DexError::SwapBinLimitExceeded))
+        );
+    }
+
+    #[test]
+    fn exact_out_swaps_charge_the_fee_on_top() {
+        // 149 A takes all of bin 0 for 102 B (100 plus the 100 bps fee on
+        // the gross input) and 49 of bin 1 at 1.01 for 51 B.
+        let mut pool = test_pool();
+        pool.fee_bps = 100;
+        let mut bin_array = bins_holding_a(&mut pool, &[0, 1]);
+        let result = swap_through_bins(
+            &mut pool,
+            &mut [&mut bin_array],
+            SwapMode::ExactOut(149),
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!((result.amount_in, result.amount_out), (153, 149));
+        assert_eq!(pool.active_bin_id, 1);
+        assert_eq!((pool.total_a_amount, pool.total_b_amount), (51, 150));
+        let bins = &bin_array.bins;
+        assert_eq!((bins[0].amount_a, bins[0].amount_b), (0, 100));
+        assert_eq!((bins[1].amount_a, bins[1].amount_b), (51, 50));
+        assert_eq!(bins[0].fee_growth_b, fee_growth_delta(2, 100));
+        assert_eq!(bins[1].fee_growth_b, fee_growth_delta(1, 100));
+
+        // Asking for more than the bins within reach hold fails rather than
+        // returning less.
+        let mut pool = test_pool();
+        let mut bin_array = bins_holding_a(&mut pool, &[0, 1]);
+        assert_eq!(
+            swap_through_bins(
+                &mut pool,
+                &mut [&mut bin_array],
+                SwapMode::ExactOut(201),
+                false,
+                0,
+            )
+            .err(),
+            Some(error!(DexError::SwapBinLimitExceeded))
+        );
+    }
+
+    #[test]
+    fn deadlines_and_active_bin_slippage_are_inclusive() {
+        assert_eq!(check_deadline_at(99, 100), Ok(()));
+        assert_eq!(check_deadline_at(100, 100), Ok(()));
+        assert_eq!(
+            check_deadline_at(101, 100),
+            err!(DexError::TransactionExpired)
+        );
+
+        let mut pool = test_pool();
+        pool.active_bin_id = -3;
+        assert_eq!(check_active_bin_slippage(&pool, -3, 0), Ok(()));
+        assert_eq!(check_active_bin_slippage(&pool, -1, 2), Ok(()));
+        assert_eq!(check_active_bin_slippage(&pool, -5, 2), Ok(()));
+        for expected in [-6, 0] {
+            assert_eq!(
+                check_active_bin_slippage(&pool, expected, 2),
+                err!(DexError::ActiveBinSlippageExceeded)
+            );
         }
-        // 5 = EUR token (frozen required)
-        5 => {
-            if mint.freeze_authority.is_none() {
-                msg!("{} mint must be frozen for EUR token", label);
-                return err!(DexError::MintNotFrozen);
-            }
+        // The drift is measured without overflowing at the ends of the range.
+        pool.active_bin_id = i32::MIN;
+        assert_eq!(check_active_bin_slippage(&pool, i32::MAX, u32::MAX), Ok(()));
+        assert_eq!(
+            check_active_bin_slippage(&pool, i32::MAX, u32::MAX - 1),
+            err!(DexError::ActiveBinSlippageExceeded)
+        );
+    }
+
+    fn parse_list<T: std::str::FromStr>(field: &str) -> Vec<T>
+    where
+        T::Err: std::fmt::Debug,
+    {
+        field
+            .split(',')
+            .map(|value| value.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn allocation_functions_match_golden_vectors() {
+        let vectors = include_str!("../../../tests/golden/allocation_functions.txt");
+        for line in vectors.lines().filter(|line| !line.starts_with('#')) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let function_type: u8 = fields[1].parse().unwrap();
+            let params: [i64; 5] = parse_list(fields[2]).try_into().unwrap();
+            let bins: u32 = fields[3].parse().unwrap();
+            let amount: u64 = fields[4].parse().unwrap();
+            validate_function_spec(function_type, &params).unwrap();
+
+            let weights: Vec<i128> = (1..=bins)
+                .map(|distance| function_weight(function_type, &params, distance, bins).unwrap())
+                .collect();
+            assert_eq!(weights, parse_list::<i128>(fields[5]), "{line}");
+
+            let distances: Vec<u32> = (1..=bins).collect();
+            let allocation = allocate_side(amount, function_type, &params, &distances).unwrap();
+            assert_eq!(allocation, parse_list::<u64>(fields[6]), "{line}");
         }
-        // 6 = Commodity proxy (frozen required)
-        6 => {
-            if mint.freeze_authority.is_none() {
-                msg!("{} mint must be frozen for commodity proxy", label);
-                return err!(DexError::MintNotFrozen);
-            }
+    }
+
+    #[test]
+    fn preset_validation_enforces_ranges() {
+        let s = PARAM_SCALE;
+        let valid = [
+            (FUNCTION_PRESET_POWER, [s, 120 * s, 2 * s + s / 2, 0, 0]),
+            (FUNCTION_PRESET_EXP, [s, 200 * s, -10 * s, 0, 0]),
+            (FUNCTION_PRESET_LOG, [s, 80 * s, 15 * s, 0, 0]),
+            (FUNCTION_PRESET_SIGMOID, [s, 200 * s, 12 * s, 650_000, 0]),
+        ];
+        for (function_type, params) in valid {
+            validate_function_spec(function_type, &params).unwrap();
         }
-        // 7 = Native token (frozen required)
-        7 => {
-            if mint.freeze_authority.is_none() {
-                msg!("{} mint must be frozen for native token", label);
-                return err!(DexError::MintNotFrozen);
-            }
+
+        let invalid = [
+            (FUNCTION_PRESET_POWER, [0, 0, s, 0, 0]),
+            (FUNCTION_PRESET_POWER, [-s, 2 * s, s, 0, 0]),
+            (FUNCTION_PRESET_POWER, [s, s, 0, 0, 0]),
+            (FUNCTION_PRESET_EXP, [s, s, 0, 0, 0]),
+            (FUNCTION_PRESET_EXP, [s, s, PRESET_MAX_STEEPNESS + 1, 0, 0]),
+            (FUNCTION_PRESET_LOG, [s, s, -s / 2, 0, 0]),
+            (FUNCTION_PRESET_SIGMOID, [s, s, 12 * s, s + 1, 0]),
+            (
+                FUNCTION_PRESET_SIGMOID,
+                [s, PRESET_MAX_WEIGHT + 1, 12 * s, 0, 0],
+            ),
+        ];
+        for (function_type, params) in invalid {
+            assert!(validate_function_spec(function_type, &params).is_err());
         }
-        _ => return err!(DexError::InvalidTokenKind),
     }
 
-    Ok(())
-}
+    #[test]
+    fn function_weights_reject_bad_ranges() {
+        let s = PARAM_SCALE;
+        let distances: Vec<u32> = (1..=10).collect();
 
-fn validate_guarantee_policy(
-    policy: u8,
-    allowed_assets_mask: u16,
-    guarantee_mint: Pubkey,
-) -> Result<()> {
-    match policy {
-        // 0 = fixed guarantee mint
-        0 => {
-            if guarantee_mint == Pubkey::default() || allowed_assets_mask != 0 {
-                return err!(DexError::InvalidGuaranteePolicy);
+        // 5 - d goes negative past d = 5.
+        let sloped = [-s, 0, 5 * s, 0, 0];
+        assert!(function_weights(FUNCTION_LINEAR, &sloped, &distances[..5]).is_ok());
+        assert_eq!(
+            function_weights(FUNCTION_LINEAR, &sloped, &distances).unwrap_err(),
+            error!(DexError::NegativeFunctionWeight)
+        );
+
+        // log(8 - d) is undefined from d = 8 onwards.
+        let log = [s, 10 * s, s, 8 * s, 5 * s];
+        assert!(function_weights(FUNCTION_LOG, &log, &distances[..7]).is_ok());
+        assert_eq!(
+            function_weights(FUNCTION_LOG, &log, &distances).unwrap_err(),
+            error!(DexError::FunctionDomainError)
+        );
+
+        let flat_zero = [0, 0, 0, 0, 0];
+        assert_eq!(
+            function_weights(FUNCTION_LINEAR, &flat_zero, &distances).unwrap_err(),
+            error!(DexError::ZeroFunctionWeights)
+        );
+        assert!(function_weights(FUNCTION_LINEAR, &flat_zero, &[]).is_ok());
+    }
+
+    #[test]
+    fn fee_growth_splits_between_lps_and_repeated_claims() {
+        let mut bin = Bin {
+            liquidity_supply: 300,
+            ..Bin::default()
+        };
+        let (small, large) = (100u128, 200u128);
+
+        bin.accrue_fee(30, true);
+        let small_owed = pending_fee(small, bin.fee_growth_a, 0).unwrap();
+        let large_owed = pending_fee(large, bin.fee_growth_a, 0).unwrap();
+        assert_eq!((small_owed, large_owed), (9, 19));
+        assert!(small_owed + large_owed <= 30);
+        assert_eq!(pending_fee(small, bin.fee_growth_b, 0).unwrap(), 0);
+
+        // After checkpointing, only fees accrued since then are owed.
+        let checkpoint = bin.fee_growth_a;
+        assert_eq!(pending_fee(small, bin.fee_growth_a, checkpoint).unwrap(), 0);
+        bin.accrue_fee(3_000, true);
+        assert_eq!(
+            pending_fee(small, bin.fee_growth_a, checkpoint).unwrap(),
+            1_000
+        );
+    }
+
+    #[test]
+    fn zero_copy_layouts_have_no_implicit_padding() {
+        assert_eq!(std::mem::size_of::<Bin>(), Bin::SIZE);
+        assert_eq!(std::mem::size_of::<BinArray>(), BinArray::SIZE);
+    }
+
+    #[test]
+    fn fee_growth_wraps() {
+        let checkpoint = u128::MAX - (5u128 << 64) + 1;
+        let growth = checkpoint.wrapping_add(7u128 << 64);
+        assert_eq!(pending_fee(10, growth, checkpoint).unwrap(), 70);
+    }
+
+    #[test]
+    fn mul_shr_64_matches_wide_product() {
+        let small = [0u128, 1, 3, 1 << 32, u64::MAX as u128];
+        for a in small {
+            for b in small {
+                assert_eq!(mul_shr_64(a, b), Some((a * b) >> 64));
             }
         }
-        // 1 = user choice from allowed assets mask
-        1 => {
-            if allowed_assets_mask == 0 || guarantee_mint != Pubkey::default() {
-                return err!(DexError::InvalidGuaranteePolicy);
+        assert_eq!(mul_shr_64(3 << 64, 5), Some(15));
+        assert_eq!(mul_shr_64(1 << 100, 1 << 27), Some(1 << 63));
+        assert_eq!(mul_shr_64(1 << 100, 1 << 91), Some(1 << 127));
+        assert_eq!(mul_shr_64(1 << 100, 1 << 92), None);
+        assert_eq!(mul_shr_64(u128::MAX, u128::MAX), None);
+    }
+
+    #[test]
+    fn fee_split_follows_protocol_share() {
+        // The default share keeps the previous hardcoded 5% house cut.
+        assert_eq!(split_fee_bps(100, DEFAULT_PROTOCOL_FEE_SHARE_BPS), (5, 95));
+        assert_eq!(split_fee_bps(30, DEFAULT_PROTOCOL_FEE_SHARE_BPS), (1, 29));
+        assert_eq!(
+            split_fee_bps(10_000, MAX_PROTOCOL_FEE_SHARE_BPS),
+            (5_000, 5_000)
+        );
+        assert_eq!(split_fee_bps(100, 0), (0, 100));
+        assert!(validate_protocol_fee_share(MAX_PROTOCOL_FEE_SHARE_BPS).is_ok());
+        assert!(validate_protocol_fee_share(MAX_PROTOCOL_FEE_SHARE_BPS + 1).is_err());
+    }
+
+    #[test]
+    fn bin_prices_are_geometric() {
+        for bin_step_bps in [1u16, 10, 25, 100, MAX_BIN_STEP_BPS] {
+            let base = 1.0 + bin_step_bps as f64 / BPS_DENOMINATOR as f64;
+            for bin_id in (-2_000..=2_000).step_by(37).chain([-1, 0, 1]) {
+                let want = base.powi(bin_id);
+                if !(1e-12..=1e12).contains(&want) {
+                    continue;
+                }
+                let got = price_from_bin_id(bin_id, bin_step_bps).unwrap() as f64 / Q64 as f64;
+                // Rounding leaves about 1e-19 absolute error per step.
+                assert!(
+                    (got - want).abs() < 1e-12 * want + 1e-18,
+                    "{bin_step_bps}/{bin_id}: {got} vs {want}"
+                );
             }
         }
-        _ => return err!(DexError::InvalidGuaranteePolicy),
+        assert_eq!(price_from_bin_id(0, 100), Ok(Q64));
+        assert_eq!(
+            price_from_bin_id(MAX_BIN_ID + 1, 1),
+            err!(DexError::Overflow)
+        );
+        assert_eq!(price_from_bin_id(100_000, 100), err!(DexError::Overflow));
     }
-    Ok(())
-}
 
-fn validate_function_spec(function_type: u8, params: &[i64; 5]) -> Result<()> {
-    match function_type {
-        FUNCTION_LINEAR => {
-            // f(x) = m(x - x0) + y0
-            // params: [m, x0, y0, unused, unused]
-            let _m = params[0];
-            let _x0 = params[1];
-            let _y0 = params[2];
-        }
-        FUNCTION_LOG => {
-            // g(x) = A * log_B(C(-x + h)) + k
-            // params: [A, B, C, h, k]
-            if params[1] == 0 || params[2] == 0 {
-                return err!(DexError::InvalidFunctionSpec);
+    #[test]
+    fn bin_id_from_price_inverts_bin_prices() {
+        for bin_step_bps in [1u16, 25, 100] {
+            for bin_id in (-2_000..=2_000).step_by(41).chain([-1, 0, 1]) {
+                let price = price_from_bin_id(bin_id, bin_step_bps).unwrap();
+                assert_eq!(bin_id_from_price(price, bin_step_bps), Ok(bin_id));
+                assert_eq!(bin_id_from_price(price - 1, bin_step_bps), Ok(bin_id - 1));
             }
         }
-        _ => return err!(DexError::InvalidFunctionSpec),
+        // 1% bins around a 1:1 pair: 91 and 111 cents land 10 bins either side.
+        let bin = |cents| bin_id_from_price(div_price(cents_to_price(cents), Q64).unwrap(), 100);
+        assert_eq!(bin(100), Ok(0));
+        assert_eq!(bin(91), Ok(-10));
+        assert_eq!(bin(111), Ok(10));
+
+        // Ranges follow token B's current price, not the one stored at
+        // creation: at $0.91 for B, a $0.91 bound is one B per A.
+        let mut pool = test_pool();
+        pool.token_b_price = cents_to_price(100);
+        assert_eq!(
+            range_bin_id(&pool, cents_to_price(91), pool.token_b_price),
+            Ok(-10)
+        );
+        assert_eq!(
+            range_bin_id(&pool, cents_to_price(91), cents_to_price(91)),
+            Ok(0)
+        );
+        // $150 against a token worth 2^-20 dollars stays representable.
+        let ratio = div_price(150 * Q64, Q64 >> 20).unwrap();
+        assert_eq!(ratio, (150 << 20) * Q64);
+        let sol = bin_id_from_price(ratio, 10).unwrap();
+        assert!(price_from_bin_id(sol, 10).unwrap() <= ratio);
+        assert!(price_from_bin_id(sol + 1, 10).unwrap() > ratio);
+        assert_eq!(bin_id_from_price(0, 100), err!(DexError::InvalidPrice));
     }
 
-    Ok(())
-}
+    #[test]
+    fn div_price_keeps_sub_cent_precision() {
+        assert_eq!(div_price(Q64, Q64), Ok(Q64));
+        assert_eq!(div_price(3 * Q64, 2 * Q64), Ok(3 * Q64 / 2));
+        // Large numerators would overflow a plain (price << 64).
+        assert_eq!(div_price(1 << 100, 1 << 90), Ok(Q64 << 10));
+        assert_eq!(div_price(u128::MAX, 1), err!(DexError::Overflow));
+        assert_eq!(div_price(1, 1 << 80), Ok(0));
+        // A $0.000001 token against $1 is no longer truncated to zero.
+        assert_eq!(div_price(Q64 / 1_000_000, Q64), Ok(Q64 / 1_000_000));
+        assert_eq!(div_price(Q64, 0), err!(DexError::InvalidPrice));
+    }
 
-fn apply_liquidity(pool: &mut Account<Pool>, amount_a: u64, amount_b: u64) -> Result<()> {
-    if amount_a == 0 && amount_b == 0 {
-        return err!(DexError::InvalidAmount);
+    #[test]
+    fn legacy_prices_widen_in_place() {
+        let (token_a_mint, token_b_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mints = Some([(token_a_mint, 9), (token_b_mint, 6)]);
+        let mut legacy = Pool::DISCRIMINATOR.to_vec();
+        legacy.resize(8 + Pool::SIZE - 53, 0);
+        // Fields on either side of the prices must keep their values.
+        legacy[8..16].copy_from_slice(&7u64.to_le_bytes());
+        legacy[48..80].copy_from_slice(token_a_mint.as_ref());
+        legacy[80..112].copy_from_slice(token_b_mint.as_ref());
+        let slot_at = legacy.len() - 8;
+        legacy[slot_at..].copy_from_slice(&42u64.to_le_bytes());
+        let offset = LEGACY_POOL_PRICES_OFFSET;
+        legacy[offset..offset + 8].copy_from_slice(&150u64.to_le_bytes());
+        legacy[offset + 8..offset + 16].copy_from_slice(&100u64.to_le_bytes());
+
+        // Pools need their own mints to fill in decimals.
+        assert_eq!(
+            widen_legacy_prices(&legacy, None),
+            err!(DexError::InvalidMigration)
+        );
+        assert_eq!(
+            widen_legacy_prices(&legacy, Some([(token_b_mint, 6), (token_a_mint, 9)])),
+            err!(DexError::InvalidMigration)
+        );
+        let migrated = widen_legacy_prices(&legacy, mints).unwrap();
+        assert_eq!(migrated.len(), 8 + Pool::SIZE);
+        assert_eq!(migrated[..offset], legacy[..offset]);
+        assert_eq!(
+            migrated[offset + 32..legacy.len() + 16],
+            legacy[offset + 16..]
+        );
+        let pool = Pool::try_deserialize(&mut migrated.as_slice()).unwrap();
+        assert_eq!(pool.token_a_price, 3 * Q64 / 2);
+        assert_eq!(pool.token_b_price, Q64);
+        assert_eq!(pool.pool_id, 7);
+        assert_eq!(pool.last_price_update_slot, 42);
+        assert_eq!((pool.token_a_decimals, pool.token_b_decimals), (9, 6));
+        assert_eq!(pool.depth_ratio_above_bps, DEFAULT_DEPTH_RATIO_BPS);
+        assert_eq!(pool.depth_ratio_below_bps, DEFAULT_DEPTH_RATIO_BPS);
+        assert_eq!(pool.flash_loan_fee_bps, DEFAULT_FLASH_LOAN_FEE_BPS);
+        assert_eq!(pool.flash_loan_amount, 0);
+        assert_eq!(pool.next_order_id, 0);
+
+        let mut legacy = Position::DISCRIMINATOR.to_vec();
+        legacy.resize(8 + Position::SIZE - 16, 0);
+        let offset = LEGACY_POSITION_PRICES_OFFSET;
+        legacy[offset..offset + 8].copy_from_slice(&91u64.to_le_bytes());
+        legacy[offset + 8..offset + 16].copy_from_slice(&111u64.to_le_bytes());
+        let migrated = widen_legacy_prices(&legacy, None).unwrap();
+        let position = Position::try_deserialize(&mut migrated.as_slice()).unwrap();
+        assert_eq!(position.min_price, cents_to_price(91));
+        assert_eq!(position.max_price, cents_to_price(111));
+
+        // Already migrated or foreign accounts are rejected.
+        assert_eq!(
+            widen_legacy_prices(&migrated, None),
+            err!(DexError::InvalidMigration)
+        );
+        assert_eq!(
+            widen_legacy_prices(&[0; 8 + Position::SIZE - 16], None),
+            err!(DexError::InvalidMigration)
+        );
     }
 
-    let total_a = pool
-        .total_a_amount
-        .checked_add(amount_a)
-        .ok_or(DexError::Overflow)?;
-    let total_b = pool
-        .total_b_amount
-        .checked_add(amount_b)
-        .ok_or(DexError::Overflow)?;
+    #[test]
+    fn mixed_decimal_values_are_normalized() {
+        // 1 wSOL (9 decimals) at $150 against 150 USDC (6 decimals) at $1.
+        let (sol, usdc) = (150 * Q64, Q64);
+        let (value_a, value_b) =
+            pool_values((1_000_000_000, 150_000_000), (sol, usdc), (9, 6)).unwrap();
+        assert_eq!(value_a, value_b);
+        // The same amounts are 1000x apart in raw units.
+        let (value_a, value_b) =
+            pool_values((1_000_000_000, 150_000_000), (sol, usdc), (6, 6)).unwrap();
+        assert_eq!(value_a, 1_000 * value_b);
+        // Either side may carry the larger decimals.
+        let (value_a, value_b) =
+            pool_values((150_000_000, 1_000_000_000), (usdc, sol), (6, 9)).unwrap();
+        assert_eq!(value_a, value_b);
+        assert_eq!(
+            pool_values((1, 1), (Q64, Q64), (0, 255)),
+            err!(DexError::Overflow)
+        );
+    }
 
-    if (amount_a == 0) ^ (amount_b == 0) {
-        let value_a = (total_a as u128)
-            .checked_mul(pool.token_a_price_cents as u128)
-            .ok_or(DexError::Overflow)?;
-        let value_b = (total_b as u128)
-            .checked_mul(pool.token_b_price_cents as u128)
-            .ok_or(DexError::Overflow)?;
+    #[test]
+    fn bins_price_base_units_of_mixed_decimal_pairs() {
+        // $150 wSOL against $1 USDC is 0.15 USDC base units per lamport.
+        let price = base_unit_price((150 * Q64, Q64), (9, 6)).unwrap();
+        assert_eq!(price, 15 * Q64 / 100);
+        // The reverse pair is 1000 / 150 lamports per USDC base unit.
+        assert_eq!(
+            base_unit_price((Q64, 150 * Q64), (6, 9)),
+            div_price(1_000 * Q64, 150 * Q64)
+        );
+        assert_eq!(base_unit_price((Q64, Q64), (6, 6)), Ok(Q64));
 
-        let (other, this_side) = if amount_a == 0 {
-            (value_a, value_b)
-        } else {
-            (value_b, value_a)
+        // One lamport in a bin is worth 0.15 USDC base units, not 150.
+        let bin_id = bin_id_from_price(price, 10).unwrap();
+        let bin_price = price_from_bin_id(bin_id, 10).unwrap();
+        assert!(bin_price <= price && price_from_bin_id(bin_id + 1, 10).unwrap() > price);
+        assert!(bin_id < 0);
+    }
+
+    #[test]
+    fn depth_ratio_bounds_one_sided_deposits() {
+        // The default ratio keeps the other side at least as deep.
+        assert!(check_depth_ratio(100, 100, DEFAULT_DEPTH_RATIO_BPS).is_ok());
+        assert_eq!(
+            check_depth_ratio(101, 100, DEFAULT_DEPTH_RATIO_BPS),
+            err!(DexError::OneSidedNotAllowed)
+        );
+        // 15_000 bps allows up to a 50% imbalance.
+        assert!(check_depth_ratio(150, 100, 15_000).is_ok());
+        assert_eq!(
+            check_depth_ratio(151, 100, 15_000),
+            err!(DexError::OneSidedNotAllowed)
+        );
+        // Tighter ratios require the other side to stay deeper.
+        assert!(check_depth_ratio(50, 100, 5_000).is_ok());
+        assert_eq!(
+            check_depth_ratio(51, 100, 5_000),
+            err!(DexError::OneSidedNotAllowed)
+        );
+        // Zero disables one-sided deposits, even into an empty side.
+        assert_eq!(
+            check_depth_ratio(0, 100, 0),
+            err!(DexError::OneSidedNotAllowed)
+        );
+    }
+
+    #[test]
+    fn one_sided_deposits_are_weighed_against_the_other_side() {
+        // 100 A above the active bin 0 and 100 B below it; the pool totals
+        // also count 900 B sitting in bins the deposit does not touch.
+        let mut pool = test_pool();
+        pool.depth_ratio_above_bps = DEFAULT_DEPTH_RATIO_BPS;
+        pool.depth_ratio_below_bps = DEFAULT_DEPTH_RATIO_BPS;
+        (pool.total_a_amount, pool.total_b_amount) = (100, 1_000);
+        let (mut lower, mut upper) = (empty_bin_array(-1), empty_bin_array(0));
+        lower.bins[BIN_ARRAY_SIZE - 1].amount_b = 60;
+        upper.bins[0] = Bin {
+            amount_a: 40,
+            amount_b: 40,
+            ..Bin::default()
         };
+        upper.bins[1].amount_a = 60;
+        let prices = (Q64, Q64);
 
-        if other < this_side {
-            return err!(DexError::OneSidedNotAllowed);
-        }
+        // The totals would take 50 more A, but the bins below hold only 100.
+        assert_eq!(
+            apply_liquidity(&mut pool, &[&lower, &upper], 50, 0, prices),
+            err!(DexError::OneSidedNotAllowed)
+        );
+        assert_eq!((pool.total_a_amount, pool.total_b_amount), (100, 1_000));
+        assert_eq!(
+            apply_liquidity(&mut pool, &[&lower, &upper], 0, 1, prices),
+            err!(DexError::OneSidedNotAllowed)
+        );
+        assert!(apply_liquidity(&mut pool, &[&lower, &upper], 1, 1, prices).is_ok());
+
+        // Once the other side is deeper, the same deposit fits.
+        lower.bins[0].amount_b = 60;
+        assert!(apply_liquidity(&mut pool, &[&lower, &upper], 50, 0, prices).is_ok());
+        assert_eq!((pool.total_a_amount, pool.total_b_amount), (151, 1_001));
     }
 
-    pool.total_a_amount = total_a;
-    pool.total_b_amount = total_b;
-    Ok(())
-}
+    #[test]
+    fn route_hops_follow_the_input_mint() {
+        let (wsol, usdc, eur) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        // wSOL -> USDC through a wSOL/USDC pool, then USDC -> EUR through an
+        // EUR/USDC pool.
+        assert_eq!(route_direction(wsol, usdc, wsol), Ok((true, usdc)));
+        assert_eq!(route_direction(eur, usdc, usdc), Ok((false, eur)));
+        assert_eq!(
+            route_direction(eur, usdc, wsol),
+            err!(DexError::InvalidRoute)
+        );
+    }
 
-fn transfer_deposit(
-    token_program: &Program<Token>,
-    owner: &Signer,
-    owner_token_a: &Account<TokenAccount>,
-    owner_token_b: &Account<TokenAccount>,
-    vault_a: &Account<TokenAccount>,
-    vault_b: &Account<TokenAccount>,
-    amount_a: u64,
-    amount_b: u64,
-) -> Result<()> {
-    if amount_a > 0 {
-        token::transfer(
-            CpiContext::new(
-                token_program.to_account_info(),
-                token::Transfer {
-                    from: owner_token_a.to_account_info(),
-                    to: vault_a.to_account_info(),
-                    authority: owner.to_account_info(),
-                },
-            ),
-            amount_a,
-        )?;
+    /// Writable bin arrays of `pool` at `keys`, as passed in remaining
+    /// accounts. The data is backed by u128s so the zero-copy struct after
+    /// the discriminator is aligned.
+    fn bin_array_infos(keys: &[Pubkey], pool: &Pubkey) -> &'static [AccountInfo<'static>] {
+        let infos = keys.iter().map(|key| {
+            let backing = vec![0u128; (16 + BinArray::SIZE).div_ceil(16)].leak();
+            let data = &mut bytemuck::cast_slice_mut::<u128, u8>(backing)[8..16 + BinArray::SIZE];
+            data[..8].copy_from_slice(&BinArray::DISCRIMINATOR);
+            data[8..40].copy_from_slice(pool.as_ref());
+            let key = Box::leak(Box::new(*key));
+            let lamports = Box::leak(Box::new(1));
+            AccountInfo::new(key, false, true, lamports, data, &crate::ID, false, 0)
+        });
+        infos.collect::<Vec<_>>().leak()
     }
 
-    if amount_b > 0 {
-        token::transfer(
-            CpiContext::new(
-                token_program.to_account_info(),
-                token::Transfer {
-                    from: owner_token_b.to_account_info(),
-                    to: vault_b.to_account_info(),
-                    authority: owner.to_account_info(),
-                },
-            ),
+    #[test]
+    fn repeated_bin_arrays_are_rejected() {
+        let pool = Pubkey::new_unique();
+        let (key, other_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let distinct = bin_array_infos(&[key, other_key], &pool);
+        assert_eq!(load_bin_arrays(&pool, distinct).unwrap().len(), 2);
+        assert_eq!(
+            load_bin_arrays(&Pubkey::new_unique(), distinct).err(),
+            Some(error!(DexError::InvalidBinArray))
+        );
+
+        // One copy would take the bin updates and the other would hide them.
+        let repeated = bin_array_infos(&[key, other_key, key], &pool);
+        assert_eq!(
+            load_bin_arrays(&pool, repeated).err(),
+            Some(error!(DexError::InvalidBinArray))
+        );
+    }
+
+    #[test]
+    fn staked_positions_cannot_withdraw() {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut empty = [];
+        let system_program = anchor_lang::system_program::ID;
+        let never_staked = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut empty,
+            &system_program,
+            false,
+            0,
+        );
+        assert_eq!(check_not_staked(&never_staked), Ok(()));
+
+        for active in [true, false] {
+            let stake = Stake {
+                pool: Pubkey::new_unique(),
+                position: Pubkey::new_unique(),
+                owner: Pubkey::new_unique(),
+                staked_at_slot: 1,
+                rebate_bps: HOUSE_FEE_REBATE_BPS,
+                active,
+                bump: 255,
+            };
+            let mut data = Vec::new();
+            stake.try_serialize(&mut data).unwrap();
+            let mut lamports = 1;
+            let info = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &crate::ID,
+                false,
+                0,
+            );
+            let expected = if active {
+                err!(DexError::AlreadyStaked)
+            } else {
+                Ok(())
+            };
+            assert_eq!(check_not_staked(&info), expected);
+        }
+    }
+
+    #[test]
+    fn flash_loan_fees_follow_lp_holdings() {
+        let mut bin_array = empty_bin_array(0);
+        let lp_bin = |amount_a, amount_b| Bin {
+            amount_a,
             amount_b,
-        )?;
+            liquidity_supply: 1,
+            ..Bin::default()
+        };
+        assert_eq!(
+            bin_array.accrue_fee_by_holdings(8, false),
+            err!(DexError::NoLiquidityForFee)
+        );
+
+        bin_array.bins[0] = lp_bin(0, 100);
+        bin_array.bins[1] = lp_bin(0, 300);
+        bin_array.bins[2] = lp_bin(500, 0);
+        // Token B without LP shares (resting orders only) earns nothing.
+        bin_array.bins[3].amount_b = 1_000;
+        bin_array.accrue_fee_by_holdings(8, false).unwrap();
+        let earned = |bin: &Bin| pending_fee(1, bin.fee_growth_b, 0).unwrap();
+        assert_eq!(earned(&bin_array.bins[0]), 2);
+        assert_eq!(earned(&bin_array.bins[1]), 6);
+        assert_eq!(earned(&bin_array.bins[2]), 0);
+        assert_eq!(bin_array.bins[3].fee_growth_b, 0);
+
+        // Borrowing checks the same holdings before lending.
+        assert_eq!(bin_array.lp_holdings(false), 400);
+        assert_eq!(bin_array.lp_holdings(true), 500);
+
+        // Rounding remainders go to the last bin, so nothing is lost.
+        bin_array.accrue_fee_by_holdings(1, false).unwrap();
+        assert_eq!(earned(&bin_array.bins[0]), 2);
+        assert_eq!(earned(&bin_array.bins[1]), 7);
+
+        // Until the repay, swaps and liquidity changes leave the bins alone.
+        let mut pool = test_pool();
+        assert_eq!(check_no_flash_loan(&pool), Ok(()));
+        pool.flash_loan_amount = 1;
+        assert_eq!(check_no_flash_loan(&pool), err!(DexError::FlashLoanActive));
     }
 
-    Ok(())
+    #[test]
+    fn flash_loan_repay_is_matched_by_pool() {
+        use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+        use anchor_lang::InstructionData;
+
+        let pool = Pubkey::new_unique();
+        let repay = |program_id, data: Vec<u8>, pool| Instruction {
+            program_id,
+            accounts: vec![AccountMeta::new(pool, false)],
+            data,
+        };
+        let repay_data = instruction::FlashLoanRepay {}.data();
+        assert!(is_flash_loan_repay(
+            &repay(crate::ID, repay_data.clone(), pool),
+            &pool
+        ));
+        // Another pool, another program or another instruction do not repay.
+        assert!(!is_flash_loan_repay(
+            &repay(crate::ID, repay_data.clone(), Pubkey::new_unique()),
+            &pool
+        ));
+        assert!(!is_flash_loan_repay(
+            &repay(Pubkey::new_unique(), repay_data, pool),
+            &pool
+        ));
+        let borrow_data = instruction::FlashLoanBorrow {
+            amount: 1,
+            borrow_a: true,
+        }
+        .data();
+        assert!(!is_flash_loan_repay(
+            &repay(crate::ID, borrow_data, pool),
+            &pool
+        ));
+
+        // The fee rounds up so small loans are never free.
+        assert_eq!(fee_on_amount(1, DEFAULT_FLASH_LOAN_FEE_BPS), Ok(1));
+        assert_eq!(
+            fee_on_amount(1_000_000, DEFAULT_FLASH_LOAN_FEE_BPS),
+            Ok(900)
+        );
+    }
+
+    #[test]
+    fn limit_orders_fill_pro_rata_by_round() {
+        let pool = test_pool();
+        let mut bin = Bin::default();
+        let place = |bin: &mut Bin, amount| {
+            let (shares, round, amount) = bin.add_order(true, amount).unwrap();
+            LimitOrder {
+                pool: Pubkey::default(),
+                owner: Pubkey::default(),
+                order_id: 0,
+                bin_id: 0,
+                sell_a: true,
+                round,
+                shares,
+                amount,
+                filled: 0,
+                bump: 0,
+            }
+        };
+
+        // A partial fill is shared pro rata and claimable once.
+        let mut first = place(&mut bin, 100);
+        let mut second = place(&mut bin, 300);
+        bin.fill_orders(true, 200, 200).unwrap();
+        assert_eq!(first.settle_fills(&pool, &mut bin), Ok(50));
+        assert_eq!(second.settle_fills(&pool, &mut bin), Ok(150));
+        assert_eq!(first.settle_fills(&pool, &mut bin), Ok(0));
+
+        // Joining a partly filled side does not share in earlier fills.
+        let mut third = place(&mut bin, 200);
+        assert_eq!((third.amount, bin.order_amount_a), (200, 400));
+        assert_eq!(first.settle_fills(&pool, &mut bin), Ok(0));
+        assert_eq!(second.withdraw_unfilled(&mut bin), Ok(150));
+        assert_eq!(bin.order_amount_a, 250);
+
+        // A complete fill ends the round; a new order starts the next one
+        // without touching the filled orders.
+        bin.fill_orders(true, 250, 250).unwrap();
+        assert_eq!((bin.order_supply_a, bin.order_round_a), (0, 1));
+        let fourth = place(&mut bin, 10);
+        assert_eq!(fourth.round, 1);
+        assert_eq!(first.settle_fills(&pool, &mut bin), Ok(50));
+        assert_eq!(third.settle_fills(&pool, &mut bin), Ok(200));
+        assert_eq!(first.withdraw_unfilled(&mut bin), Ok(0));
+        assert_eq!(bin.order_amount_a, 10);
+
+        // An order worth a fraction of a token is credited, and charged, the
+        // rounded-up amount, none of which counts as filled.
+        let mut bin = Bin::default();
+        place(&mut bin, 3);
+        bin.fill_orders(true, 1, 1).unwrap();
+        let mut fifth = place(&mut bin, 1);
+        assert_eq!((fifth.shares, fifth.amount, bin.order_amount_a), (1, 1, 3));
+        assert_eq!(fifth.settle_fills(&pool, &mut bin), Ok(0));
+
+        // Claims are priced at the bin but paid from what swaps paid in, so
+        // a fill paid a unit short leaves the last claim a unit short.
+        let mut bin = Bin::default();
+        let mut first = place(&mut bin, 100);
+        let mut second = place(&mut bin, 100);
+        bin.fill_orders(true, 200, 199).unwrap();
+        assert_eq!(first.settle_fills(&pool, &mut bin), Ok(100));
+        assert_eq!(second.settle_fills(&pool, &mut bin), Ok(99));
+        assert_eq!(bin.order_proceeds_a, 0);
+    }
+
+    #[test]
+    fn bins_holding_only_orders_charge_the_house_share() {
+        let mut pool = test_pool();
+        pool.fee_bps = 100;
+        pool.protocol_fee_share_bps = 5_000;
+        let mut bin_array = empty_bin_array(0);
+        bin_array.bins[1].add_order(true, 1_000).unwrap();
+
+        // 101 B buys A from the orders one bin up at 1.01, paying 50 bps
+        // (half of the 100 bps fee), all of it to the house.
+        let result = swap_through_bins(
+            &mut pool,
+            &mut [&mut bin_array],
+            SwapMode::ExactIn(101),
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!((result.amount_in, result.amount_out), (101, 99));
+        assert_eq!(pool.house_fees_b, 1);
+        assert_eq!(bin_array.bins[1].order_amount_a, 901);
+        assert_eq!(bin_array.bins[1].order_proceeds_a, 100);
+        assert_eq!(bin_array.bins[1].fee_growth_b, 0);
+
+        // With LPs in the bin the full fee is charged and the house keeps
+        // only its share.
+        let mut pool = test_pool();
+        pool.fee_bps = 100;
+        pool.protocol_fee_share_bps = 5_000;
+        let mut bin_array = empty_bin_array(0);
+        bin_array.bins[1].add_order(true, 500).unwrap();
+        bin_array.bins[1].amount_a = 500;
+        bin_array.bins[1].liquidity_supply = 500;
+        pool.total_a_amount = 500;
+        swap_through_bins(
+            &mut pool,
+            &mut [&mut bin_array],
+            SwapMode::ExactIn(400),
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!(pool.house_fees_b, 2);
+        assert_eq!(bin_array.bins[1].fee_growth_b, fee_growth_delta(2, 500));
+    }
+
+    #[test]
+    fn observations_average_the_active_price() {
+        let mut observations = Observations {
+            pool: Pubkey::new_unique(),
+            index: 0,
+            bump: 0,
+            observations: vec![Observation {
+                slot: 100,
+                cumulative_price: 0,
+            }],
+        };
+        observations.grow(3);
+        // Price 1 for slots 100..110, then 3 for 110..120, then 2 after.
+        observations.record(110, Q64);
+        observations.record(110, 5 * Q64);
+        observations.record(120, 3 * Q64);
+        assert_eq!(observations.newest().cumulative_price, 40 * Q64);
+
+        // Averages interpolate inside the history and extrapolate after it.
+        assert_eq!(observations.twap(120, 20, 2 * Q64), Ok(2 * Q64));
+        assert_eq!(observations.twap(130, 10, 2 * Q64), Ok(2 * Q64));
+        assert_eq!(observations.twap(125, 10, 2 * Q64), Ok(5 * Q64 / 2));
+        assert_eq!(observations.twap(115, 10, 2 * Q64), Ok(2 * Q64));
+        assert_eq!(
+            observations.twap(120, 21, 2 * Q64),
+            err!(DexError::ObservationTooOld)
+        );
+        assert_eq!(
+            observations.twap(120, 0, 2 * Q64),
+            err!(DexError::InvalidAmount)
+        );
+
+        // A full ring overwrites the oldest entry.
+        observations.record(130, 2 * Q64);
+        assert_eq!(observations.twap(130, 20, 2 * Q64), Ok(5 * Q64 / 2));
+        assert_eq!(
+            observations.twap(130, 21, 2 * Q64),
+            err!(DexError::ObservationTooOld)
+        );
+
+        // Growing keeps history in order ahead of the new entries.
+        observations.grow(5);
+        assert_eq!(
+            observations.observations[observations.index as usize].slot,
+            130
+        );
+        observations.record(140, 2 * Q64);
+        assert_eq!(observations.twap(140, 30, 2 * Q64), Ok(7 * Q64 / 3));
+    }
+
+    #[test]
+    fn reference_price_updates_are_bounded() {
+        // 10% either way is the largest accepted move.
+        assert!(check_price_deviation(1_000, 1_100).is_ok());
+        assert!(check_price_deviation(1_000, 900).is_ok());
+        assert_eq!(
+            check_price_deviation(1_000, 1_101),
+            err!(DexError::PriceDeviationTooLarge)
+        );
+        assert_eq!(
+            check_price_deviation(1_000, 899),
+            err!(DexError::PriceDeviationTooLarge)
+        );
+        assert_eq!(
+            check_price_deviation(1_000, 0),
+            err!(DexError::InvalidPrice)
+        );
+        assert!(check_price_deviation(5, 5).is_ok());
+    }
+
+    #[test]
+    fn dynamic_fee_tracks_volatility() {
+        let mut dynamic_fee = DynamicFee {
+            filter_period: 10,
+            decay_period: 100,
+            reduction_factor: 5_000,
+            // 1 bps per bin^2 of accumulated volatility.
+            variable_fee_control: 10_000,
+            max_volatility_accumulator: 20 * VOLATILITY_SCALE,
+            max_total_fee_bps: 200,
+            ..DynamicFee::default()
+        };
+
+        // A first swap sets the reference bin and crosses 4 bins from it.
+        dynamic_fee.update_references(100, 1_000);
+        dynamic_fee.update_volatility_accumulator(100);
+        assert_eq!(dynamic_fee.total_fee_bps(30), 30);
+        dynamic_fee.update_volatility_accumulator(104);
+        assert_eq!(dynamic_fee.volatility_accumulator, 4 * VOLATILITY_SCALE);
+        assert_eq!(dynamic_fee.total_fee_bps(30), 46);
+
+        // Within the filter period the reference bin is kept.
+        dynamic_fee.update_references(104, 1_005);
+        dynamic_fee.update_volatility_accumulator(106);
+        assert_eq!(dynamic_fee.volatility_accumulator, 6 * VOLATILITY_SCALE);
+
+        // Between filter and decay periods half the accumulator carries over.
+        dynamic_fee.update_references(106, 1_050);
+        assert_eq!(dynamic_fee.index_reference, 106);
+        assert_eq!(dynamic_fee.volatility_reference, 3 * VOLATILITY_SCALE);
+        dynamic_fee.update_volatility_accumulator(107);
+        assert_eq!(dynamic_fee.total_fee_bps(30), 46);
+
+        // Large moves are capped by the accumulator and total fee bounds.
+        dynamic_fee.update_volatility_accumulator(150);
+        assert_eq!(dynamic_fee.volatility_accumulator, 20 * VOLATILITY_SCALE);
+        assert_eq!(dynamic_fee.total_fee_bps(30), 200);
+
+        // After the decay period volatility resets.
+        dynamic_fee.update_references(150, 1_500);
+        dynamic_fee.update_volatility_accumulator(150);
+        assert_eq!(dynamic_fee.volatility_accumulator, 0);
+        assert_eq!(dynamic_fee.total_fee_bps(30), 30);
+    }
 }
@@ -0,0 +1,295 @@
+//! Deterministic fixed-point math for allocation curves.
+//!
+//! Values are `i128` scaled by [`ONE`] (1e12). Every routine uses integer
+//! arithmetic only, truncates toward zero, and returns `None` on invalid input
+//! or overflow, so results are identical on every validator.
+//!
+//! Error bounds (checked against f64 references in the tests below):
+//! - [`ln`]: absolute error below 1e-10.
+//! - [`log_b`]: absolute error below 1e-10 * (1 + |log_b(x)|) / |ln(b)|.
+//! - [`exp`]: relative error below 1e-10, or absolute error below 2e-12 for
+//!   results under 1e-2.
+//! - [`pow`]: relative error below 1e-10 * (1 + |y * ln(x)|).
+//! - [`sqrt`]: exact floor of the true square root at this scale.
+
+use crate::PARAM_SCALE;
+
+pub const ONE: i128 = 1_000_000_000_000;
+pub const LN_2: i128 = 693_147_180_560;
+
+/// Largest argument accepted by [`exp`]; e^x must fit in `i128` at [`ONE`] scale.
+pub const EXP_MAX: i128 = 60 * ONE;
+/// Below this argument [`exp`] truncates to zero.
+pub const EXP_MIN: i128 = -28 * ONE;
+
+const PARAM_TO_FIXED: i128 = ONE / PARAM_SCALE as i128;
+
+/// Converts a PARAM_SCALE value to [`ONE`] scale.
+pub fn from_param(value: i64) -> i128 {
+    value as i128 * PARAM_TO_FIXED
+}
+
+/// Converts a [`ONE`] scale value back to PARAM_SCALE, truncating toward zero.
+pub fn to_param(value: i128) -> Option<i64> {
+    i64::try_from(value / PARAM_TO_FIXED).ok()
+}
+
+pub fn mul(a: i128, b: i128) -> Option<i128> {
+    Some(a.checked_mul(b)? / ONE)
+}
+
+pub fn div(a: i128, b: i128) -> Option<i128> {
+    if b == 0 {
+        return None;
+    }
+    Some(a.checked_mul(ONE)? / b)
+}
+
+/// Natural logarithm. `x` must be positive.
+pub fn ln(x: i128) -> Option<i128> {
+    if x <= 0 {
+        return None;
+    }
+    // Reduce to y = x / 2^k in [1, 2), then ln(x) = k * ln(2) + ln(y).
+    let mut k = (ONE.leading_zeros() as i32) - (x.leading_zeros() as i32);
+    let mut y = shift(x, -k);
+    if y >= 2 * ONE {
+        k += 1;
+        y = shift(x, -k);
+    } else if y < ONE {
+        k -= 1;
+        y = shift(x, -k);
+    }
+
+    // ln(y) = 2 * atanh(z) = 2 * (z + z^3/3 + z^5/5 + ...), z = (y - 1) / (y + 1) in [0, 1/3).
+    let z = (y - ONE) * ONE / (y + ONE);
+    let z2 = z * z / ONE;
+    let mut term = z;
+    let mut series = 0;
+    let mut n = 1;
+    while term != 0 {
+        series += term / n;
+        term = term * z2 / ONE;
+        n += 2;
+    }
+    Some(k as i128 * LN_2 + 2 * series)
+}
+
+/// Logarithm of `x` in base `base`. Both must be positive and `base != 1`.
+pub fn log_b(x: i128, base: i128) -> Option<i128> {
+    let ln_base = ln(base)?;
+    if ln_base == 0 {
+        return None;
+    }
+    div(ln(x)?, ln_base)
+}
+
+/// e^x for `x <= EXP_MAX`.
+pub fn exp(x: i128) -> Option<i128> {
+    if x > EXP_MAX {
+        return None;
+    }
+    if x < EXP_MIN {
+        return Some(0);
+    }
+    // Reduce to r = x - k * ln(2) with |r| <= ln(2) / 2, then e^x = e^r * 2^k.
+    let half = if x >= 0 { LN_2 / 2 } else { -LN_2 / 2 };
+    let k = (x + half) / LN_2;
+    let r = x - k * LN_2;
+
+    let mut sum = ONE;
+    let mut term = ONE;
+    let mut n = 1;
+    while term != 0 {
+        term = term * r / ONE / n;
+        sum += term;
+        n += 1;
+    }
+    if k >= 0 {
+        sum.checked_mul(1i128.checked_shl(k as u32)?)
+    } else {
+        Some(sum >> (-k) as u32)
+    }
+}
+
+/// x^y for `x >= 0`. `0^y` is 0 for positive `y`, 1 for `y = 0`, and invalid
+/// for negative `y`.
+pub fn pow(x: i128, y: i128) -> Option<i128> {
+    if x < 0 {
+        return None;
+    }
+    if x == 0 {
+        return match y.signum() {
+            1 => Some(0),
+            0 => Some(ONE),
+            _ => None,
+        };
+    }
+    if y == 0 {
+        return Some(ONE);
+    }
+    exp(mul(y, ln(x)?)?)
+}
+
+/// Square root of `x >= 0`, rounded down.
+pub fn sqrt(x: i128) -> Option<i128> {
+    if x < 0 {
+        return None;
+    }
+    let scaled = x.checked_mul(ONE)? as u128;
+    Some(isqrt(scaled) as i128)
+}
+
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = 1u128 << ((128 - n.leading_zeros()) / 2 + 1);
+    loop {
+        let y = (x + n / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+fn shift(value: i128, bits: i32) -> i128 {
+    if bits >= 0 {
+        value << bits
+    } else {
+        value >> -bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_f64(value: i128) -> f64 {
+        value as f64 / ONE as f64
+    }
+
+    fn fixed(value: f64) -> i128 {
+        (value * ONE as f64).round() as i128
+    }
+
+    /// Geometric grid of `steps` points spanning [lo, hi].
+    fn grid(lo: f64, hi: f64, steps: usize) -> Vec<f64> {
+        let ratio = (hi / lo).powf(1.0 / (steps - 1) as f64);
+        (0..steps).map(|i| lo * ratio.powi(i as i32)).collect()
+    }
+
+    #[test]
+    fn ln_matches_f64() {
+        let mut inputs: Vec<i128> = (1..=2_000).map(|i| i * ONE / 100).collect();
+        inputs.extend(grid(1e-12, 1e26, 4_000).into_iter().map(fixed));
+        inputs.extend([1, 2, 3, ONE - 1, ONE, ONE + 1, 2 * ONE - 1, 2 * ONE]);
+        for x in inputs.into_iter().filter(|x| *x > 0) {
+            let got = to_f64(ln(x).unwrap());
+            let want = to_f64(x).ln();
+            assert!((got - want).abs() < 1e-10, "ln({x}): {got} vs {want}");
+        }
+    }
+
+    #[test]
+    fn ln_rejects_non_positive() {
+        assert_eq!(ln(0), None);
+        assert_eq!(ln(-ONE), None);
+        assert_eq!(ln(ONE), Some(0));
+    }
+
+    #[test]
+    fn log_b_matches_f64() {
+        for base in [0.5, 1.5, 2.0, std::f64::consts::E, 10.0, 16.0, 1000.0] {
+            for x in grid(1e-6, 1e12, 500) {
+                let got = to_f64(log_b(fixed(x), fixed(base)).unwrap());
+                let want = to_f64(fixed(x)).ln() / base.ln();
+                let bound = 1e-10 * (1.0 + want.abs()) / base.ln().abs();
+                assert!(
+                    (got - want).abs() < bound,
+                    "log_{base}({x}): {got} vs {want}"
+                );
+            }
+        }
+        assert_eq!(log_b(fixed(8.0), ONE), None);
+        assert_eq!(log_b(fixed(8.0), 0), None);
+    }
+
+    #[test]
+    fn exp_matches_f64() {
+        let mut x = EXP_MIN;
+        while x <= EXP_MAX {
+            let got = to_f64(exp(x).unwrap());
+            let want = to_f64(x).exp();
+            if want >= 1e-2 {
+                assert!(
+                    ((got - want) / want).abs() < 1e-10,
+                    "exp({x}): {got} vs {want}"
+                );
+            } else {
+                assert!((got - want).abs() < 2e-12, "exp({x}): {got} vs {want}");
+            }
+            x += ONE / 97;
+        }
+    }
+
+    #[test]
+    fn exp_bounds() {
+        assert_eq!(exp(0), Some(ONE));
+        assert_eq!(exp(EXP_MAX + 1), None);
+        assert_eq!(exp(EXP_MIN - 1), Some(0));
+        assert!(exp(EXP_MAX).is_some());
+    }
+
+    #[test]
+    fn pow_matches_f64() {
+        for x in grid(1e-3, 1e3, 120) {
+            for y in (-40..=40).map(|i| i as f64 / 8.0) {
+                let want = to_f64(fixed(x)).powf(y);
+                if !(1e-2..=1e20).contains(&want) {
+                    continue;
+                }
+                let got = to_f64(pow(fixed(x), fixed(y)).unwrap());
+                let bound = 1e-10 * (1.0 + (y * x.ln()).abs());
+                assert!(
+                    ((got - want) / want).abs() < bound,
+                    "{x}^{y}: {got} vs {want}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pow_edge_cases() {
+        assert_eq!(pow(0, ONE), Some(0));
+        assert_eq!(pow(0, 0), Some(ONE));
+        assert_eq!(pow(0, -ONE), None);
+        assert_eq!(pow(-ONE, ONE), None);
+        assert_eq!(pow(fixed(7.5), 0), Some(ONE));
+    }
+
+    #[test]
+    fn sqrt_is_exact_floor() {
+        let mut inputs: Vec<i128> = (0..=5_000).collect();
+        inputs.extend(grid(1e-12, 1e14, 2_000).into_iter().map(fixed));
+        for x in inputs {
+            let root = sqrt(x).unwrap() as u128;
+            let scaled = (x * ONE) as u128;
+            assert!(
+                root * root <= scaled && (root + 1) * (root + 1) > scaled,
+                "sqrt({x})"
+            );
+            let want = to_f64(x).sqrt();
+            assert!((to_f64(root as i128) - want).abs() <= 1e-12 + want * 1e-15);
+        }
+        assert_eq!(sqrt(-1), None);
+    }
+
+    #[test]
+    fn param_round_trip() {
+        for value in [-5 * PARAM_SCALE, -1, 0, 1, PARAM_SCALE, 123_456_789] {
+            assert_eq!(to_param(from_param(value)), Some(value));
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Price oracle readers.
+//!
+//! A pool with `oracle_kind != ORACLE_KIND_NONE` values deposits at the prices
+//! published in its `oracle_a` / `oracle_b` accounts instead of the static
+//! Q64.64 prices set at `create_pool`. New oracle kinds plug in through
+//! [`read_price`].
+//!
+//! `ORACLE_KIND_PYTH_STYLE` accounts use a fixed little-endian layout carrying
+//! the Pyth aggregate fields:
+//!
+//! | offset | field          | type |
+//! |--------|----------------|------|
+//! | 0      | magic          | u32  |
+//! | 4      | version        | u32  |
+//! | 8      | price          | i64  |
+//! | 16     | conf           | u64  |
+//! | 24     | expo           | i32  |
+//! | 28     | publish_time   | i64  |
+
+use anchor_lang::prelude::*;
+
+use crate::{DexError, BPS_DENOMINATOR, ORACLE_KIND_PYTH_STYLE, Q64};
+
+pub const PRICE_ACCOUNT_MAGIC: u32 = 0xa1b2_c3d4;
+pub const PRICE_ACCOUNT_SIZE: usize = 4 + 4 + 8 + 8 + 4 + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OraclePrice {
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+impl OraclePrice {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < PRICE_ACCOUNT_SIZE || read_u32(data, 0) != PRICE_ACCOUNT_MAGIC {
+            return err!(DexError::InvalidOracle);
+        }
+        Ok(Self {
+            price: read_u64(data, 8) as i64,
+            conf: read_u64(data, 16),
+            expo: read_u32(data, 24) as i32,
+            publish_time: read_u64(data, 28) as i64,
+        })
+    }
+
+    /// Rejects non-positive prices, prices older than `max_age_secs` at `now`,
+    /// and confidence intervals wider than `max_confidence_bps` of the price.
+    pub fn validate(&self, now: i64, max_age_secs: u32, max_confidence_bps: u16) -> Result<()> {
+        if self.price <= 0 {
+            return err!(DexError::InvalidOracle);
+        }
+        if now.saturating_sub(self.publish_time) > max_age_secs as i64 {
+            return err!(DexError::StaleOraclePrice);
+        }
+        let conf_bps = self.conf as u128 * BPS_DENOMINATOR as u128;
+        if conf_bps > self.price as u128 * max_confidence_bps as u128 {
+            return err!(DexError::OracleConfidenceTooWide);
+        }
+        Ok(())
+    }
+
+    /// Price as Q64.64, truncated. Prices below 2^-64 are rejected.
+    pub fn to_price(&self) -> Result<u128> {
+        let price = self.price as u128;
+        let scale = 10u128
+            .checked_pow(self.expo.unsigned_abs())
+            .ok_or(DexError::InvalidOracle)?;
+        let fixed = if self.expo >= 0 {
+            price
+                .checked_mul(scale)
+                .and_then(|value| value.checked_mul(Q64))
+        } else {
+            (price << 64).checked_div(scale)
+        }
+        .ok_or(DexError::Overflow)?;
+        if fixed == 0 {
+            return err!(DexError::InvalidPrice);
+        }
+        Ok(fixed)
+    }
+}
+
+/// Reads a validated Q64.64 price from `account` for the given oracle kind.
+pub fn read_price(
+    oracle_kind: u8,
+    account: &AccountInfo,
+    now: i64,
+    max_age_secs: u32,
+    max_confidence_bps: u16,
+) -> Result<u128> {
+    let price = match oracle_kind {
+        ORACLE_KIND_PYTH_STYLE => OraclePrice::parse(&account.try_borrow_data()?)?,
+        _ => return err!(DexError::InvalidOracle),
+    };
+    price.validate(now, max_age_secs, max_confidence_bps)?;
+    price.to_price()
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_price_account(price: i64, conf: u64, expo: i32, publish_time: i64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PRICE_ACCOUNT_SIZE);
+        data.extend(PRICE_ACCOUNT_MAGIC.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend(price.to_le_bytes());
+        data.extend(conf.to_le_bytes());
+        data.extend(expo.to_le_bytes());
+        data.extend(publish_time.to_le_bytes());
+        data
+    }
+
+    fn read(data: &mut [u8], now: i64) -> Result<u128> {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let account = AccountInfo::new(&key, false, false, &mut lamports, data, &owner, false, 0);
+        read_price(ORACLE_KIND_PYTH_STYLE, &account, now, 60, 100)
+    }
+
+    #[test]
+    fn reads_fresh_price_as_q64() {
+        // $1.25 with 8 decimals.
+        let mut data = mock_price_account(125_000_000, 100_000, -8, 1_000);
+        assert_eq!(read(&mut data, 1_030), Ok(5 * Q64 / 4));
+
+        let mut data = mock_price_account(25, 0, 3, 1_000);
+        assert_eq!(read(&mut data, 1_000), Ok(25_000 * Q64));
+
+        // Sub-cent prices keep their precision.
+        let mut data = mock_price_account(7, 0, -9, 1_000);
+        assert_eq!(read(&mut data, 1_000), Ok((7 * Q64) / 1_000_000_000));
+    }
+
+    #[test]
+    fn rejects_stale_or_uncertain_prices() {
+        let mut data = mock_price_account(125_000_000, 100_000, -8, 1_000);
+        assert_eq!(read(&mut data, 1_061), err!(DexError::StaleOraclePrice));
+
+        // 1% of the price is the largest confidence interval accepted.
+        let mut data = mock_price_account(100_000_000, 1_000_000, -8, 1_000);
+        assert_eq!(read(&mut data, 1_000), Ok(Q64));
+        let mut data = mock_price_account(100_000_000, 1_000_001, -8, 1_000);
+        assert_eq!(
+            read(&mut data, 1_000),
+            err!(DexError::OracleConfidenceTooWide)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_accounts() {
+        let mut data = mock_price_account(-5, 0, -8, 1_000);
+        assert_eq!(read(&mut data, 1_000), err!(DexError::InvalidOracle));
+
+        let mut data = mock_price_account(100, 0, -8, 1_000);
+        data[0] = 0;
+        assert_eq!(read(&mut data, 1_000), err!(DexError::InvalidOracle));
+        assert_eq!(read(&mut data[..20], 1_000), err!(DexError::InvalidOracle));
+
+        // Prices that truncate to zero cannot be valued.
+        let mut data = mock_price_account(1, 0, -20, 1_000);
+        assert_eq!(read(&mut data, 1_000), err!(DexError::InvalidPrice));
+    }
+}
@@ -1,6 +1,7 @@
 import * as anchor from "@coral-xyz/anchor";
 import { PublicKey, SystemProgram } from "@solana/web3.js";
 import { expect } from "chai";
+import { createHash } from "crypto";
 
 describe("origin_dex", () => {
   const provider = anchor.AnchorProvider.env();
@@ -10,6 +11,13 @@ describe("origin_dex", () => {
     process.env.ORIGIN_DEX_PROGRAM_ID || "Orig1nDex111111111111111111111111111111111"
   );
 
+  // Instructions emit events with emit_cpi!, which appends the event authority
+  // PDA and the program itself to their accounts.
+  const eventCpiAccounts = [
+    { name: "eventAuthority", isMut: false, isSigner: false },
+    { name: "program", isMut: false, isSigner: false }
+  ];
+
   const idl = {
     version: "0.1.0",
     name: "origin_dex",
@@ -19,7 +27,8 @@ describe("origin_dex", () => {
         accounts: [
           { name: "config", isMut: true, isSigner: false },
           { name: "admin", isMut: true, isSigner: true },
-          { name: "systemProgram", isMut: false, isSigner: false }
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: []
       },
@@ -28,13 +37,15 @@ describe("origin_dex", () => {
         accounts: [
           { name: "registry", isMut: true, isSigner: false },
           { name: "admin", isMut: true, isSigner: true },
-          { name: "systemProgram", isMut: false, isSigner: false }
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: []
       },
       {
         name: "createPool",
         accounts: [
+          { name: "config", isMut: false, isSigner: false },
           { name: "registry", isMut: true, isSigner: false },
           { name: "pool", isMut: true, isSigner: false },
           { name: "tokenAMint", isMut: false, isSigner: false },
@@ -45,12 +56,14 @@ describe("origin_dex", () => {
           { name: "systemProgram", isMut: false, isSigner: false },
           { name: "associatedTokenProgram", isMut: false, isSigner: false },
           { name: "tokenProgram", isMut: false, isSigner: false },
-          { name: "rent", isMut: false, isSigner: false }
+          { name: "rent", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: [
           { name: "feeBps", type: "u16" },
-          { name: "tokenAPriceCents", type: "u64" },
-          { name: "tokenBPriceCents", type: "u64" },
+          { name: "binStepBps", type: "u16" },
+          { name: "tokenAPrice", type: "u128" },
+          { name: "tokenBPrice", type: "u128" },
           { name: "tokenAKind", type: "u8" },
           { name: "tokenBKind", type: "u8" },
           { name: "guaranteePolicy", type: "u8" },
@@ -58,6 +71,47 @@ describe("origin_dex", () => {
           { name: "guaranteeMint", type: "publicKey" }
         ]
       },
+      {
+        name: "initializeBinArray",
+        accounts: [
+          { name: "pool", isMut: false, isSigner: false },
+          { name: "binArray", isMut: true, isSigner: false },
+          { name: "payer", isMut: true, isSigner: true },
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "index", type: "i64" }]
+      },
+      {
+        name: "initializeObservations",
+        accounts: [
+          { name: "pool", isMut: false, isSigner: false },
+          { name: "observations", isMut: true, isSigner: false },
+          { name: "payer", isMut: true, isSigner: true },
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: []
+      },
+      {
+        name: "growObservations",
+        accounts: [
+          { name: "observations", isMut: true, isSigner: false },
+          { name: "payer", isMut: true, isSigner: true },
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "capacity", type: "u16" }]
+      },
+      {
+        name: "twap",
+        accounts: [
+          { name: "pool", isMut: false, isSigner: false },
+          { name: "observations", isMut: false, isSigner: false }
+        ],
+        args: [{ name: "windowSlots", type: "u64" }],
+        returns: "u128"
+      },
       {
         name: "createLpPosition",
         accounts: [
@@ -69,23 +123,31 @@ describe("origin_dex", () => {
           { name: "ownerTokenB", isMut: true, isSigner: false },
           { name: "vaultA", isMut: true, isSigner: false },
           { name: "vaultB", isMut: true, isSigner: false },
+          { name: "binArrayLower", isMut: true, isSigner: false },
+          { name: "binArrayUpper", isMut: true, isSigner: false },
           { name: "tokenAMint", isMut: false, isSigner: false },
           { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "oracleA", isMut: false, isSigner: false, isOptional: true },
+          { name: "oracleB", isMut: false, isSigner: false, isOptional: true },
           { name: "owner", isMut: true, isSigner: true },
           { name: "tokenProgram", isMut: false, isSigner: false },
           { name: "associatedTokenProgram", isMut: false, isSigner: false },
           { name: "systemProgram", isMut: false, isSigner: false },
-          { name: "rent", isMut: false, isSigner: false }
+          { name: "rent", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: [
-          { name: "minPriceCents", type: "u64" },
-          { name: "maxPriceCents", type: "u64" },
+          { name: "minPrice", type: "u128" },
+          { name: "maxPrice", type: "u128" },
           { name: "leftFunctionType", type: "u8" },
           { name: "leftParams", type: { array: ["i64", 5] } },
           { name: "rightFunctionType", type: "u8" },
           { name: "rightParams", type: { array: ["i64", 5] } },
           { name: "amountA", type: "u64" },
-          { name: "amountB", type: "u64" }
+          { name: "amountB", type: "u64" },
+          { name: "expectedActiveBinId", type: "i32" },
+          { name: "maxActiveBinSlippage", type: "u32" },
+          { name: "expiresAtSlot", type: "u64" }
         ]
       },
       {
@@ -101,7 +163,8 @@ describe("origin_dex", () => {
           { name: "tokenProgram", isMut: false, isSigner: false },
           { name: "associatedTokenProgram", isMut: false, isSigner: false },
           { name: "systemProgram", isMut: false, isSigner: false },
-          { name: "rent", isMut: false, isSigner: false }
+          { name: "rent", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: []
       },
@@ -115,7 +178,8 @@ describe("origin_dex", () => {
           { name: "ownerLpTokenAccount", isMut: true, isSigner: false },
           { name: "lpMint", isMut: false, isSigner: false },
           { name: "owner", isMut: true, isSigner: true },
-          { name: "tokenProgram", isMut: false, isSigner: false }
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: []
       },
@@ -130,26 +194,350 @@ describe("origin_dex", () => {
           { name: "ownerTokenB", isMut: true, isSigner: false },
           { name: "vaultA", isMut: true, isSigner: false },
           { name: "vaultB", isMut: true, isSigner: false },
+          { name: "binArrayLower", isMut: true, isSigner: false },
+          { name: "binArrayUpper", isMut: true, isSigner: false },
           { name: "tokenAMint", isMut: false, isSigner: false },
           { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "oracleA", isMut: false, isSigner: false, isOptional: true },
+          { name: "oracleB", isMut: false, isSigner: false, isOptional: true },
           { name: "owner", isMut: true, isSigner: true },
-          { name: "tokenProgram", isMut: false, isSigner: false }
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: [
           { name: "amountA", type: "u64" },
-          { name: "amountB", type: "u64" }
+          { name: "amountB", type: "u64" },
+          { name: "expectedActiveBinId", type: "i32" },
+          { name: "maxActiveBinSlippage", type: "u32" },
+          { name: "expiresAtSlot", type: "u64" }
         ]
       },
       {
-        name: "closePosition",
+        name: "swap",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "observations", isMut: true, isSigner: false },
+          { name: "userTokenA", isMut: true, isSigner: false },
+          { name: "userTokenB", isMut: true, isSigner: false },
+          { name: "vaultA", isMut: true, isSigner: false },
+          { name: "vaultB", isMut: true, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false },
+          { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "user", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "amountIn", type: "u64" },
+          { name: "minAmountOut", type: "u64" },
+          { name: "swapForB", type: "bool" },
+          { name: "expiresAtSlot", type: "u64" }
+        ],
+        returns: "u64"
+      },
+      {
+        name: "swapExactOut",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "observations", isMut: true, isSigner: false },
+          { name: "userTokenA", isMut: true, isSigner: false },
+          { name: "userTokenB", isMut: true, isSigner: false },
+          { name: "vaultA", isMut: true, isSigner: false },
+          { name: "vaultB", isMut: true, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false },
+          { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "user", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "amountOut", type: "u64" },
+          { name: "maxAmountIn", type: "u64" },
+          { name: "swapForB", type: "bool" },
+          { name: "expiresAtSlot", type: "u64" }
+        ],
+        returns: "u64"
+      },
+      {
+        name: "swapRoute",
+        accounts: [
+          { name: "userTokenIn", isMut: true, isSigner: false },
+          { name: "userTokenOut", isMut: true, isSigner: false },
+          { name: "user", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "amountIn", type: "u64" },
+          { name: "minAmountOut", type: "u64" },
+          { name: "binArrayCounts", type: "bytes" },
+          { name: "expiresAtSlot", type: "u64" }
+        ],
+        returns: "u64"
+      },
+      {
+        name: "removeLiquidity",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "position", isMut: true, isSigner: false },
+          { name: "ownerLpTokenAccount", isMut: false, isSigner: false },
+          { name: "stake", isMut: false, isSigner: false },
+          { name: "ownerTokenA", isMut: true, isSigner: false },
+          { name: "ownerTokenB", isMut: true, isSigner: false },
+          { name: "vaultA", isMut: true, isSigner: false },
+          { name: "vaultB", isMut: true, isSigner: false },
+          { name: "binArrayLower", isMut: true, isSigner: false },
+          { name: "binArrayUpper", isMut: true, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false },
+          { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "owner", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "bps", type: "u16" },
+          { name: "minAmountA", type: "u64" },
+          { name: "minAmountB", type: "u64" },
+          { name: "expiresAtSlot", type: "u64" }
+        ]
+      },
+      {
+        name: "claimFees",
         accounts: [
           { name: "pool", isMut: false, isSigner: false },
           { name: "position", isMut: true, isSigner: false },
+          { name: "holderLpTokenAccount", isMut: false, isSigner: false },
+          { name: "holderTokenA", isMut: true, isSigner: false },
+          { name: "holderTokenB", isMut: true, isSigner: false },
+          { name: "vaultA", isMut: true, isSigner: false },
+          { name: "vaultB", isMut: true, isSigner: false },
+          { name: "binArrayLower", isMut: false, isSigner: false },
+          { name: "binArrayUpper", isMut: false, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false },
+          { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "holder", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: []
+      },
+      {
+        name: "closePosition",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "position", isMut: true, isSigner: false },
           { name: "lpMint", isMut: true, isSigner: false },
           { name: "ownerLpTokenAccount", isMut: true, isSigner: false },
-          { name: "stake", isMut: true, isSigner: false },
+          { name: "stake", isMut: false, isSigner: false },
+          { name: "ownerTokenA", isMut: true, isSigner: false },
+          { name: "ownerTokenB", isMut: true, isSigner: false },
+          { name: "vaultA", isMut: true, isSigner: false },
+          { name: "vaultB", isMut: true, isSigner: false },
+          { name: "binArrayLower", isMut: true, isSigner: false },
+          { name: "binArrayUpper", isMut: true, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false },
+          { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "owner", isMut: true, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: []
+      },
+      {
+        name: "withdrawHouseFees",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "vaultA", isMut: true, isSigner: false },
+          { name: "vaultB", isMut: true, isSigner: false },
+          { name: "treasuryTokenA", isMut: true, isSigner: false },
+          { name: "treasuryTokenB", isMut: true, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false },
+          { name: "tokenBMint", isMut: false, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: []
+      },
+      {
+        name: "setProtocolFeeShare",
+        accounts: [
+          { name: "config", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "protocolFeeShareBps", type: "u16" }]
+      },
+      {
+        name: "setPoolProtocolFeeShare",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "protocolFeeShareBps", type: "u16" }]
+      },
+      {
+        name: "setDynamicFeeParams",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "filterPeriod", type: "u16" },
+          { name: "decayPeriod", type: "u16" },
+          { name: "reductionFactor", type: "u16" },
+          { name: "variableFeeControl", type: "u32" },
+          { name: "maxVolatilityAccumulator", type: "u32" },
+          { name: "maxTotalFeeBps", type: "u16" }
+        ]
+      },
+      {
+        name: "setPoolOracles",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "oracleKind", type: "u8" },
+          { name: "oracleA", type: "publicKey" },
+          { name: "oracleB", type: "publicKey" },
+          { name: "maxOracleAgeSecs", type: "u32" },
+          { name: "maxOracleConfidenceBps", type: "u16" }
+        ]
+      },
+      {
+        name: "updateReferencePrices",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "tokenAPrice", type: "u128" },
+          { name: "tokenBPrice", type: "u128" }
+        ]
+      },
+      {
+        name: "setDepthRatios",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "depthRatioAboveBps", type: "u32" },
+          { name: "depthRatioBelowBps", type: "u32" }
+        ]
+      },
+      {
+        name: "setFlashLoanFee",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "admin", isMut: false, isSigner: true },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "flashLoanFeeBps", type: "u16" }]
+      },
+      {
+        name: "flashLoanBorrow",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "vault", isMut: true, isSigner: false },
+          { name: "borrowerToken", isMut: true, isSigner: false },
+          { name: "binArray", isMut: false, isSigner: false },
+          { name: "borrower", isMut: false, isSigner: true },
+          { name: "instructions", isMut: false, isSigner: false },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "amount", type: "u64" },
+          { name: "borrowA", type: "bool" }
+        ]
+      },
+      {
+        name: "flashLoanRepay",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "vault", isMut: true, isSigner: false },
+          { name: "borrowerToken", isMut: true, isSigner: false },
+          { name: "binArray", isMut: true, isSigner: false },
+          { name: "borrower", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: []
+      },
+      {
+        name: "placeLimitOrder",
+        accounts: [
+          { name: "pool", isMut: true, isSigner: false },
+          { name: "order", isMut: true, isSigner: false },
+          { name: "binArray", isMut: true, isSigner: false },
+          { name: "vault", isMut: true, isSigner: false },
+          { name: "ownerToken", isMut: true, isSigner: false },
           { name: "owner", isMut: true, isSigner: true },
-          { name: "tokenProgram", isMut: false, isSigner: false }
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [
+          { name: "binId", type: "i32" },
+          { name: "sellA", type: "bool" },
+          { name: "amount", type: "u64" },
+          { name: "expiresAtSlot", type: "u64" }
+        ]
+      },
+      {
+        name: "claimFilledOrder",
+        accounts: [
+          { name: "pool", isMut: false, isSigner: false },
+          { name: "order", isMut: true, isSigner: false },
+          { name: "binArray", isMut: true, isSigner: false },
+          { name: "vault", isMut: true, isSigner: false },
+          { name: "ownerToken", isMut: true, isSigner: false },
+          { name: "owner", isMut: false, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "expiresAtSlot", type: "u64" }]
+      },
+      {
+        name: "cancelLimitOrder",
+        accounts: [
+          { name: "pool", isMut: false, isSigner: false },
+          { name: "order", isMut: true, isSigner: false },
+          { name: "binArray", isMut: true, isSigner: false },
+          { name: "vaultIn", isMut: true, isSigner: false },
+          { name: "ownerTokenIn", isMut: true, isSigner: false },
+          { name: "vaultOut", isMut: true, isSigner: false },
+          { name: "ownerTokenOut", isMut: true, isSigner: false },
+          { name: "owner", isMut: true, isSigner: true },
+          { name: "tokenProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
+        ],
+        args: [{ name: "expiresAtSlot", type: "u64" }]
+      },
+      {
+        name: "migrateLegacyPrices",
+        accounts: [
+          { name: "config", isMut: false, isSigner: false },
+          { name: "account", isMut: true, isSigner: false },
+          { name: "tokenAMint", isMut: false, isSigner: false, isOptional: true },
+          { name: "tokenBMint", isMut: false, isSigner: false, isOptional: true },
+          { name: "admin", isMut: true, isSigner: true },
+          { name: "systemProgram", isMut: false, isSigner: false },
+          ...eventCpiAccounts
         ],
         args: []
       }
@@ -158,14 +546,54 @@ describe("origin_dex", () => {
 
   const program = new anchor.Program(idl, programId, provider);
 
+  const [eventAuthority] = PublicKey.findProgramAddressSync(
+    [Buffer.from("__event_authority")],
+    programId
+  );
+  const eventCpi = { eventAuthority, program: programId };
+
+  // emit_cpi! records each event as a self-invocation whose data starts with
+  // Anchor's event instruction tag followed by the event discriminator.
+  const confirmed = { commitment: "confirmed" as const };
+  const EVENT_IX_TAG = "e445a52e51cb9a1d";
+  const eventDiscriminator = (name: string) =>
+    createHash("sha256")
+      .update(`event:${name}`)
+      .digest()
+      .subarray(0, 8)
+      .toString("hex");
+  const expectEvents = async (signature: string, names: string[]) => {
+    const tx = await provider.connection.getTransaction(signature, {
+      ...confirmed,
+      maxSupportedTransactionVersion: 0
+    });
+    const keys = tx!.transaction.message.staticAccountKeys;
+    const emitted = (tx!.meta!.innerInstructions ?? [])
+      .flatMap((inner) => inner.instructions)
+      .filter((ix) => keys[ix.programIdIndex].equals(programId))
+      .map((ix) => Buffer.from(anchor.utils.bytes.bs58.decode(ix.data)))
+      .filter((data) => data.subarray(0, 8).toString("hex") === EVENT_IX_TAG)
+      .map((data) => data.subarray(8, 16).toString("hex"));
+    expect(emitted).to.deep.equal(names.map(eventDiscriminator));
+  };
+
+  // Prices are Q64.64 USD values.
+  const priceFromCents = (cents: number) =>
+    new anchor.BN(cents).shln(64).divn(100);
+  const readU128 = (data: Buffer, offset: number) =>
+    data.readBigUInt64LE(offset) +
+    (data.readBigUInt64LE(offset + 8) << BigInt(64));
+
   const decodeConfig = (data: Buffer) => {
-    if (data.length < 8 + 32 + 1 + 1) {
+    if (data.length < 8 + 32 + 1 + 1 + 32 + 2) {
       throw new Error("Config data too short");
     }
     const admin = new PublicKey(data.slice(8, 8 + 32));
     const bump = data.readUInt8(8 + 32);
     const initialized = data.readUInt8(8 + 32 + 1) === 1;
-    return { admin, bump, initialized };
+    const treasury = new PublicKey(data.slice(8 + 32 + 2, 8 + 32 + 2 + 32));
+    const protocolFeeShareBps = data.readUInt16LE(8 + 32 + 2 + 32);
+    return { admin, bump, initialized, treasury, protocolFeeShareBps };
   };
 
   const decodeRegistry = (data: Buffer) => {
@@ -180,7 +608,7 @@ describe("origin_dex", () => {
   };
 
   const decodePool = (data: Buffer) => {
-    if (data.length < 8 + 8 + 32 + 32 + 32 + 1 + 1 + 1 + 1 + 2 + 2 + 2 + 8 + 1 + 2 + 32 + 8 + 8 + 8 + 8 + 32 + 32 + 8 + 1) {
+    if (data.length < 8 + 8 + 32 + 32 + 32 + 1 + 1 + 1 + 1 + 2 + 2 + 2 + 2 + 1 + 2 + 32 + 16 + 16 + 8 + 8 + 32 + 32 + 8 + 1 + 4 + 8 + 8 + 2 + 36 + 1 + 32 + 32 + 4 + 2 + 8 + 1 + 1 + 4 + 4 + 2 + 8 + 8 + 1) {
       throw new Error("Pool data too short");
     }
     const poolId = Number(data.readBigUInt64LE(8));
@@ -196,36 +624,47 @@ describe("origin_dex", () => {
     const feeBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 4);
     const lpFeeBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 6);
     const houseFeeBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 8);
-    const binSpacingMilliCents = Number(
-      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 10)
-    );
-    const guaranteePolicy = data.readUInt8(8 + 8 + 32 + 32 + 32 + 18);
-    const allowedAssetsMask = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 19);
+    const binStepBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 10);
+    const guaranteePolicy = data.readUInt8(8 + 8 + 32 + 32 + 32 + 12);
+    const allowedAssetsMask = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 13);
     const guaranteeMint = new PublicKey(
-      data.slice(8 + 8 + 32 + 32 + 32 + 21, 8 + 8 + 32 + 32 + 32 + 53)
-    );
-    const tokenAPriceCents = Number(
-      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 53)
-    );
-    const tokenBPriceCents = Number(
-      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 61)
+      data.slice(8 + 8 + 32 + 32 + 32 + 15, 8 + 8 + 32 + 32 + 32 + 47)
     );
+    const tokenAPrice = readU128(data, 8 + 8 + 32 + 32 + 32 + 47);
+    const tokenBPrice = readU128(data, 8 + 8 + 32 + 32 + 32 + 63);
     const totalAAmount = Number(
-      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 69)
+      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 79)
     );
     const totalBAmount = Number(
-      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 77)
+      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 87)
     );
     const vaultA = new PublicKey(
-      data.slice(8 + 8 + 32 + 32 + 32 + 85, 8 + 8 + 32 + 32 + 32 + 117)
+      data.slice(8 + 8 + 32 + 32 + 32 + 95, 8 + 8 + 32 + 32 + 32 + 127)
     );
     const vaultB = new PublicKey(
-      data.slice(8 + 8 + 32 + 32 + 32 + 117, 8 + 8 + 32 + 32 + 32 + 149)
+      data.slice(8 + 8 + 32 + 32 + 32 + 127, 8 + 8 + 32 + 32 + 32 + 159)
     );
     const nextPositionId = Number(
-      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 149)
+      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 159)
+    );
+    const bump = data.readUInt8(8 + 8 + 32 + 32 + 32 + 167);
+    const activeBinId = data.readInt32LE(8 + 8 + 32 + 32 + 32 + 168);
+    const houseFeesA = Number(data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 172));
+    const houseFeesB = Number(data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 180));
+    const protocolFeeShareBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 188);
+    const variableFeeControl = data.readUInt32LE(8 + 8 + 32 + 32 + 32 + 196);
+    const maxTotalFeeBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 204);
+    const tokenADecimals = data.readUInt8(8 + 8 + 32 + 32 + 32 + 305);
+    const tokenBDecimals = data.readUInt8(8 + 8 + 32 + 32 + 32 + 306);
+    const depthRatioAboveBps = data.readUInt32LE(8 + 8 + 32 + 32 + 32 + 307);
+    const depthRatioBelowBps = data.readUInt32LE(8 + 8 + 32 + 32 + 32 + 311);
+    const flashLoanFeeBps = data.readUInt16LE(8 + 8 + 32 + 32 + 32 + 315);
+    const flashLoanAmount = Number(
+      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 317)
+    );
+    const nextOrderId = Number(
+      data.readBigUInt64LE(8 + 8 + 32 + 32 + 32 + 334)
     );
-    const bump = data.readUInt8(8 + 8 + 32 + 32 + 32 + 157);
     return {
       poolId,
       creator,
@@ -238,23 +677,36 @@ describe("origin_dex", () => {
       feeBps,
       lpFeeBps,
       houseFeeBps,
-      binSpacingMilliCents,
+      binStepBps,
       guaranteePolicy,
       allowedAssetsMask,
       guaranteeMint,
-      tokenAPriceCents,
-      tokenBPriceCents,
+      tokenAPrice,
+      tokenBPrice,
       totalAAmount,
       totalBAmount,
       vaultA,
       vaultB,
       nextPositionId,
-      bump
+      bump,
+      activeBinId,
+      houseFeesA,
+      houseFeesB,
+      protocolFeeShareBps,
+      variableFeeControl,
+      maxTotalFeeBps,
+      tokenADecimals,
+      tokenBDecimals,
+      depthRatioAboveBps,
+      depthRatioBelowBps,
+      flashLoanFeeBps,
+      flashLoanAmount,
+      nextOrderId
     };
   };
 
   const decodePosition = (data: Buffer) => {
-    if (data.length < 32 + 32 + 8 + 32 + 8 + 8 + 1 + 1 + (8 * 5) + (8 * 5) + 8 + 8 + 1) {
+    if (data.length < 32 + 32 + 8 + 32 + 16 + 16 + 1 + 1 + (8 * 5) + (8 * 5) + 8 + 8 + 1) {
       throw new Error("Position data too short");
     }
     const pool = new PublicKey(data.slice(8, 8 + 32));
@@ -263,18 +715,14 @@ describe("origin_dex", () => {
     const lpMint = new PublicKey(
       data.slice(8 + 32 + 32 + 8, 8 + 32 + 32 + 8 + 32)
     );
-    const minPriceCents = Number(
-      data.readBigUInt64LE(8 + 32 + 32 + 8 + 32)
-    );
-    const maxPriceCents = Number(
-      data.readBigUInt64LE(8 + 32 + 32 + 8 + 32 + 8)
-    );
-    const leftFunctionType = data.readUInt8(8 + 32 + 32 + 8 + 32 + 16);
-    const rightFunctionType = data.readUInt8(8 + 32 + 32 + 8 + 32 + 17);
+    const minPrice = readU128(data, 8 + 32 + 32 + 8 + 32);
+    const maxPrice = readU128(data, 8 + 32 + 32 + 8 + 32 + 16);
+    const leftFunctionType = data.readUInt8(8 + 32 + 32 + 8 + 32 + 32);
+    const rightFunctionType = data.readUInt8(8 + 32 + 32 + 8 + 32 + 33);
 
     const leftParams: bigint[] = [];
     const rightParams: bigint[] = [];
-    let offset = 8 + 32 + 32 + 8 + 32 + 18;
+    let offset = 8 + 32 + 32 + 8 + 32 + 34;
     for (let i = 0; i < 5; i += 1) {
       leftParams.push(data.readBigInt64LE(offset));
       offset += 8;
@@ -294,8 +742,8 @@ describe("origin_dex", () => {
       owner,
       positionId,
       lpMint,
-      minPriceCents,
-      maxPriceCents,
+      minPrice,
+      maxPrice,
       leftFunctionType,
       rightFunctionType,
       leftParams,
@@ -322,14 +770,16 @@ describe("origin_dex", () => {
       return;
     }
 
-    await program.methods
+    const initSig = await program.methods
       .initialize()
       .accounts({
         config,
         admin: provider.wallet.publicKey,
-        systemProgram: SystemProgram.programId
+        systemProgram: SystemProgram.programId,
+        ...eventCpi
       })
-      .rpc();
+      .rpc(confirmed);
+    await expectEvents(initSig, ["ConfigInitialized"]);
 
     const after = await provider.connection.getAccountInfo(config);
     expect(after).to.not.equal(null);
@@ -338,6 +788,10 @@ describe("origin_dex", () => {
       provider.wallet.publicKey.toBase58()
     );
     expect(parsed.initialized).to.equal(true);
+    expect(parsed.treasury.toBase58()).to.equal(
+      provider.wallet.publicKey.toBase58()
+    );
+    expect(parsed.protocolFeeShareBps).to.equal(500);
   });
 
   it("initializes registry and creates a pool", async () => {
@@ -348,14 +802,16 @@ describe("origin_dex", () => {
 
     let registryAccount = await provider.connection.getAccountInfo(registry);
     if (!registryAccount) {
-      await program.methods
+      const registrySig = await program.methods
         .initRegistry()
         .accounts({
           registry,
           admin: provider.wallet.publicKey,
-          systemProgram: SystemProgram.programId
+          systemProgram: SystemProgram.programId,
+          ...eventCpi
         })
-        .rpc();
+        .rpc(confirmed);
+      await expectEvents(registrySig, ["RegistryInitialized"]);
       registryAccount = await provider.connection.getAccountInfo(registry);
     }
 
@@ -406,6 +862,25 @@ describe("origin_dex", () => {
       return;
     }
 
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      programId
+    );
+    // SPL mints store decimals at byte 44. Token B is priced so one base unit
+    // of each token is worth the same, which puts the active bin at 0.
+    const mintDecimals = async (mint: PublicKey) =>
+      (await provider.connection.getAccountInfo(mint))!.data.readUInt8(44);
+    const tokenADecimals = await mintDecimals(tokenAMint);
+    const tokenBDecimals = await mintDecimals(tokenBMint);
+    const decimalShift = new anchor.BN(10).pow(
+      new anchor.BN(Math.abs(tokenBDecimals - tokenADecimals))
+    );
+    const tokenAPrice = priceFromCents(100);
+    const tokenBPrice =
+      tokenBDecimals >= tokenADecimals
+        ? tokenAPrice.mul(decimalShift)
+        : tokenAPrice.div(decimalShift);
+
     const poolBefore = await provider.connection.getAccountInfo(pool);
     if (!poolBefore) {
       const guaranteePolicy = 1; // user choice
@@ -414,11 +889,12 @@ describe("origin_dex", () => {
       const tokenAKind = 4; // USDC
       const tokenBKind = 3; // WSOL
 
-      await program.methods
+      const createPoolSig = await program.methods
         .createPool(
           100,
-          new anchor.BN(100),
-          new anchor.BN(100),
+          100,
+          tokenAPrice,
+          tokenBPrice,
           tokenAKind,
           tokenBKind,
           guaranteePolicy,
@@ -426,6 +902,7 @@ describe("origin_dex", () => {
           guaranteeMint
         )
         .accounts({
+          config,
           registry,
           pool,
           tokenAMint,
@@ -436,9 +913,11 @@ describe("origin_dex", () => {
           systemProgram: SystemProgram.programId,
           associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
           tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
-          rent: anchor.web3.SYSVAR_RENT_PUBKEY
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          ...eventCpi
         })
-        .rpc();
+        .rpc(confirmed);
+      await expectEvents(createPoolSig, ["PoolCreated"]);
     }
 
     const poolAfter = await provider.connection.getAccountInfo(pool);
@@ -457,19 +936,127 @@ describe("origin_dex", () => {
     expect(poolParsed.feeBps).to.equal(100);
     expect(poolParsed.houseFeeBps).to.equal(5);
     expect(poolParsed.lpFeeBps).to.equal(95);
-    expect(poolParsed.binSpacingMilliCents).to.equal(1000);
+    expect(poolParsed.protocolFeeShareBps).to.equal(500);
+    expect(poolParsed.variableFeeControl).to.equal(0);
+    expect(poolParsed.maxTotalFeeBps).to.equal(100);
+    expect(poolParsed.binStepBps).to.equal(100);
     expect(poolParsed.guaranteePolicy).to.equal(1);
     expect(poolParsed.allowedAssetsMask).to.equal(0b11);
     expect(poolParsed.guaranteeMint.toBase58()).to.equal(
       PublicKey.default.toBase58()
     );
-    expect(poolParsed.tokenAPriceCents).to.equal(100);
-    expect(poolParsed.tokenBPriceCents).to.equal(100);
+    expect(poolParsed.tokenAPrice.toString()).to.equal(tokenAPrice.toString());
+    expect(poolParsed.tokenBPrice.toString()).to.equal(tokenBPrice.toString());
+    expect(poolParsed.tokenADecimals).to.equal(tokenADecimals);
+    expect(poolParsed.tokenBDecimals).to.equal(tokenBDecimals);
+    expect(poolParsed.depthRatioAboveBps).to.equal(10_000);
+    expect(poolParsed.depthRatioBelowBps).to.equal(10_000);
+    expect(poolParsed.flashLoanFeeBps).to.equal(9);
+    expect(poolParsed.flashLoanAmount).to.equal(0);
     expect(poolParsed.totalAAmount).to.equal(0);
     expect(poolParsed.totalBAmount).to.equal(0);
     expect(poolParsed.vaultA.toBase58()).to.equal(poolVaultA.toBase58());
     expect(poolParsed.vaultB.toBase58()).to.equal(poolVaultB.toBase58());
     expect(poolParsed.nextPositionId).to.equal(0);
+    expect(poolParsed.activeBinId).to.equal(0);
+
+    // A per-pool override recomputes the split; restore the default after.
+    const setPoolShare = async (shareBps: number) => {
+      const signature = await program.methods
+        .setPoolProtocolFeeShare(shareBps)
+        .accounts({ config, pool, admin: provider.wallet.publicKey, ...eventCpi })
+        .rpc(confirmed);
+      await expectEvents(signature, ["PoolProtocolFeeShareUpdated"]);
+    };
+    await setPoolShare(2_000);
+    const poolOverridden = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    expect(poolOverridden.protocolFeeShareBps).to.equal(2_000);
+    expect(poolOverridden.houseFeeBps).to.equal(20);
+    expect(poolOverridden.lpFeeBps).to.equal(80);
+    await setPoolShare(500);
+
+    // Re-applying a default still records the update.
+    const protocolShareSig = await program.methods
+      .setProtocolFeeShare(500)
+      .accounts({ config, admin: provider.wallet.publicKey, ...eventCpi })
+      .rpc(confirmed);
+    await expectEvents(protocolShareSig, ["ProtocolFeeShareUpdated"]);
+    const flashLoanFeeSig = await program.methods
+      .setFlashLoanFee(9)
+      .accounts({ config, pool, admin: provider.wallet.publicKey, ...eventCpi })
+      .rpc(confirmed);
+    await expectEvents(flashLoanFeeSig, ["FlashLoanFeeUpdated"]);
+
+    // Depth ratios are tuned per side of the active bin; restore the default.
+    const setDepthRatios = async (aboveBps: number, belowBps: number) => {
+      const signature = await program.methods
+        .setDepthRatios(aboveBps, belowBps)
+        .accounts({ config, pool, admin: provider.wallet.publicKey, ...eventCpi })
+        .rpc(confirmed);
+      await expectEvents(signature, ["DepthRatiosUpdated"]);
+    };
+    await setDepthRatios(15_000, 0);
+    const poolDepthTuned = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    expect(poolDepthTuned.depthRatioAboveBps).to.equal(15_000);
+    expect(poolDepthTuned.depthRatioBelowBps).to.equal(0);
+    await setDepthRatios(10_000, 10_000);
+
+    // The variable fee cap can never drop below the base fee.
+    let rejected = false;
+    try {
+      await program.methods
+        .setDynamicFeeParams(30, 600, 5_000, 10_000, 350_000, 99)
+        .accounts({ config, pool, admin: provider.wallet.publicKey, ...eventCpi })
+        .rpc();
+    } catch (err) {
+      rejected = true;
+    }
+    expect(rejected).to.equal(true);
+
+    // Depth ratios are capped at MAX_DEPTH_RATIO_BPS.
+    rejected = false;
+    try {
+      await setDepthRatios(100_001, 10_000);
+    } catch (err) {
+      rejected = true;
+    }
+    expect(rejected).to.equal(true);
+
+    // Reference price corrections are limited to 10% per update.
+    rejected = false;
+    try {
+      await program.methods
+        .updateReferencePrices(tokenAPrice.muln(2), tokenBPrice)
+        .accounts({ config, pool, admin: provider.wallet.publicKey, ...eventCpi })
+        .rpc();
+    } catch (err) {
+      rejected = true;
+    }
+    expect(rejected).to.equal(true);
+
+    // Pools created with Q64.64 prices have nothing to migrate.
+    rejected = false;
+    try {
+      await program.methods
+        .migrateLegacyPrices()
+        .accounts({
+          config,
+          account: pool,
+          tokenAMint,
+          tokenBMint,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+          ...eventCpi
+        })
+        .rpc();
+    } catch (err) {
+      rejected = true;
+    }
+    expect(rejected).to.equal(true);
 
     const registryAfter = await provider.connection.getAccountInfo(registry);
     expect(registryAfter).to.not.equal(null);
@@ -519,15 +1106,71 @@ describe("origin_dex", () => {
       programId
     );
 
-    const minPriceCents = new anchor.BN(90);
-    const maxPriceCents = new anchor.BN(110);
+    // The active bin is 0 with 1% bins, so a $0.91..$1.11 range for token A
+    // spans bins -10..10 in bin arrays -1 and 0.
+    const binArrayPda = (index: number) => {
+      const indexSeed = Buffer.alloc(8);
+      indexSeed.writeBigInt64LE(BigInt(index));
+      return PublicKey.findProgramAddressSync(
+        [Buffer.from("bin_array"), pool.toBuffer(), indexSeed],
+        programId
+      )[0];
+    };
+    const binArrayLower = binArrayPda(-1);
+    const binArrayUpper = binArrayPda(0);
+    for (const [index, binArray] of [
+      [-1, binArrayLower],
+      [0, binArrayUpper]
+    ] as [number, PublicKey][]) {
+      if (!(await provider.connection.getAccountInfo(binArray))) {
+        const binArraySig = await program.methods
+          .initializeBinArray(new anchor.BN(index))
+          .accounts({
+            pool,
+            binArray,
+            payer: provider.wallet.publicKey,
+            systemProgram: SystemProgram.programId,
+            ...eventCpi
+          })
+          .rpc(confirmed);
+        await expectEvents(binArraySig, ["BinArrayInitialized"]);
+      }
+    }
+
+    // Swaps record the active price into the pool's observation buffer.
+    const [observations] = PublicKey.findProgramAddressSync(
+      [Buffer.from("observations"), pool.toBuffer()],
+      programId
+    );
+    if (!(await provider.connection.getAccountInfo(observations))) {
+      const observationsSig = await program.methods
+        .initializeObservations()
+        .accounts({
+          pool,
+          observations,
+          payer: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+          ...eventCpi
+        })
+        .rpc(confirmed);
+      await expectEvents(observationsSig, ["ObservationsInitialized"]);
+    }
+
+    const expiresAtSlot = new anchor.BN(
+      (await provider.connection.getSlot()) + 150
+    );
+
+    const minPrice = priceFromCents(91);
+    const maxPrice = priceFromCents(111);
     const leftFunctionType = 1; // linear
     const rightFunctionType = 2; // log
     const scale = new anchor.BN(1_000_000);
+    // Left: f(d) = 20 - d, right: g(d) = log10(100 - d); both stay positive
+    // over the 10 bins on each side of the active bin.
     const leftParams = [
-      new anchor.BN(1).mul(scale),
-      new anchor.BN(100).mul(scale),
+      new anchor.BN(-1).mul(scale),
       new anchor.BN(0),
+      new anchor.BN(20).mul(scale),
       new anchor.BN(0),
       new anchor.BN(0)
     ];
@@ -539,16 +1182,19 @@ describe("origin_dex", () => {
       new anchor.BN(0)
     ];
 
-    await program.methods
+    const createPositionSig = await program.methods
       .createLpPosition(
-        minPriceCents,
-        maxPriceCents,
+        minPrice,
+        maxPrice,
         leftFunctionType,
         leftParams,
         rightFunctionType,
         rightParams,
         new anchor.BN(10),
-        new anchor.BN(10)
+        new anchor.BN(10),
+        poolParsed.activeBinId,
+        0,
+        expiresAtSlot
       )
       .accounts({
         pool,
@@ -568,21 +1214,27 @@ describe("origin_dex", () => {
         }),
         vaultA: poolVaultA,
         vaultB: poolVaultB,
+        binArrayLower,
+        binArrayUpper,
         tokenAMint,
         tokenBMint,
+        oracleA: null,
+        oracleB: null,
         owner: provider.wallet.publicKey,
         tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
         associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
-        rent: anchor.web3.SYSVAR_RENT_PUBKEY
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        ...eventCpi
       })
-      .rpc();
+      .rpc(confirmed);
+    await expectEvents(createPositionSig, ["PositionCreated"]);
 
     const positionInfo = await provider.connection.getAccountInfo(position);
     expect(positionInfo).to.not.equal(null);
     const parsedPosition = decodePosition(positionInfo!.data);
-    expect(parsedPosition.minPriceCents).to.equal(90);
-    expect(parsedPosition.maxPriceCents).to.equal(110);
+    expect(parsedPosition.minPrice.toString()).to.equal(minPrice.toString());
+    expect(parsedPosition.maxPrice.toString()).to.equal(maxPrice.toString());
     expect(parsedPosition.leftFunctionType).to.equal(1);
     expect(parsedPosition.rightFunctionType).to.equal(2);
     expect(parsedPosition.amountA).to.equal(10);
@@ -592,7 +1244,7 @@ describe("origin_dex", () => {
       [Buffer.from("stake"), position.toBuffer()],
       programId
     );
-    await program.methods
+    const stakeSig = await program.methods
       .stakeLpNft()
       .accounts({
         pool,
@@ -611,11 +1263,13 @@ describe("origin_dex", () => {
         tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
         associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
-        rent: anchor.web3.SYSVAR_RENT_PUBKEY
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        ...eventCpi
       })
-      .rpc();
+      .rpc(confirmed);
+    await expectEvents(stakeSig, ["LpNftStaked"]);
 
-    await program.methods
+    const unstakeSig = await program.methods
       .unstakeLpNft()
       .accounts({
         pool,
@@ -631,57 +1285,481 @@ describe("origin_dex", () => {
         }),
         lpMint,
         owner: provider.wallet.publicKey,
-        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
       })
-      .rpc();
+      .rpc(confirmed);
+    await expectEvents(unstakeSig, ["LpNftUnstaked"]);
 
-    await program.methods
-      .addLiquidityToPosition(new anchor.BN(5), new anchor.BN(5))
+    const addLiquidityAccounts = {
+      pool,
+      position,
+      lpMint,
+      ownerLpTokenAccount: anchor.utils.token.associatedAddress({
+        mint: lpMint,
+        owner: provider.wallet.publicKey
+      }),
+      ownerTokenA: anchor.utils.token.associatedAddress({
+        mint: tokenAMint,
+        owner: provider.wallet.publicKey
+      }),
+      ownerTokenB: anchor.utils.token.associatedAddress({
+        mint: tokenBMint,
+        owner: provider.wallet.publicKey
+      }),
+      vaultA: poolVaultA,
+      vaultB: poolVaultB,
+      binArrayLower,
+      binArrayUpper,
+      tokenAMint,
+      tokenBMint,
+      oracleA: null,
+      oracleB: null,
+      owner: provider.wallet.publicKey,
+      tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      ...eventCpi
+    };
+    const addLiquidity = (accounts: typeof addLiquidityAccounts) =>
+      program.methods
+        .addLiquidityToPosition(
+          new anchor.BN(5),
+          new anchor.BN(5),
+          poolParsed.activeBinId,
+          0,
+          expiresAtSlot
+        )
+        .accounts(accounts)
+        .rpc(confirmed);
+
+    // Another mint's token accounts cannot stand in for token A, even when
+    // the vault is the pool's own account for that mint.
+    let foreignMintRejected = false;
+    try {
+      await addLiquidity({
+        ...addLiquidityAccounts,
+        ownerTokenA: addLiquidityAccounts.ownerTokenB,
+        vaultA: poolVaultB,
+        tokenAMint: tokenBMint
+      });
+    } catch (err) {
+      foreignMintRejected = true;
+    }
+    expect(foreignMintRejected).to.equal(true);
+
+    const addLiquiditySig = await addLiquidity(addLiquidityAccounts);
+    await expectEvents(addLiquiditySig, ["LiquidityAdded"]);
+
+    const poolAfterLiquidity = await provider.connection.getAccountInfo(pool);
+    expect(poolAfterLiquidity).to.not.equal(null);
+    const parsedPoolAfter = decodePool(poolAfterLiquidity!.data);
+    expect(parsedPoolAfter.totalAAmount).to.equal(15);
+    expect(parsedPoolAfter.totalBAmount).to.equal(15);
+
+    // Buying A with 3 B at bin 1 (1.01 B per A): 1 B of fee at 100 bps leaves
+    // 2 B, which buys a single unit of A without moving past bin 1.
+    const swapAccounts = {
+      pool,
+      observations,
+      userTokenA: anchor.utils.token.associatedAddress({
+        mint: tokenAMint,
+        owner: provider.wallet.publicKey
+      }),
+      userTokenB: anchor.utils.token.associatedAddress({
+        mint: tokenBMint,
+        owner: provider.wallet.publicKey
+      }),
+      vaultA: poolVaultA,
+      vaultB: poolVaultB,
+      tokenAMint,
+      tokenBMint,
+      user: provider.wallet.publicKey,
+      tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      ...eventCpi
+    };
+    const swapBinArrays = [
+      { pubkey: binArrayLower, isWritable: true, isSigner: false },
+      { pubkey: binArrayUpper, isWritable: true, isSigner: false }
+    ];
+
+    // A single-hop route through the same pool quotes the same output: the
+    // pool, its observations, its B (input) and A (output) vaults, then its
+    // two bin arrays.
+    const routeAmountOut = await program.methods
+      .swapRoute(
+        new anchor.BN(3),
+        new anchor.BN(1),
+        Buffer.from([2]),
+        expiresAtSlot
+      )
+      .accounts({
+        userTokenIn: swapAccounts.userTokenB,
+        userTokenOut: swapAccounts.userTokenA,
+        user: provider.wallet.publicKey,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
+      })
+      .remainingAccounts([
+        { pubkey: pool, isWritable: true, isSigner: false },
+        { pubkey: observations, isWritable: true, isSigner: false },
+        { pubkey: poolVaultB, isWritable: true, isSigner: false },
+        { pubkey: poolVaultA, isWritable: true, isSigner: false },
+        ...swapBinArrays
+      ])
+      .view();
+    expect(routeAmountOut.toNumber()).to.equal(1);
+
+    const swapSig = await program.methods
+      .swap(new anchor.BN(3), new anchor.BN(1), false, expiresAtSlot)
+      .accounts(swapAccounts)
+      .remainingAccounts(swapBinArrays)
+      .rpc(confirmed);
+    await expectEvents(swapSig, ["Swapped"]);
+
+    const poolAfterSwap = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    // Only the 2 B net of fee enter the bin; the fee accrues to its LPs.
+    expect(poolAfterSwap.activeBinId).to.equal(1);
+    expect(poolAfterSwap.totalAAmount).to.equal(14);
+    expect(poolAfterSwap.totalBAmount).to.equal(17);
+
+    // The last unit of A in bin 1 costs ceil(1.01) = 2 B plus fee = 3 B.
+    const amountIn = await program.methods
+      .swapExactOut(new anchor.BN(1), new anchor.BN(4), false, expiresAtSlot)
+      .accounts(swapAccounts)
+      .remainingAccounts(swapBinArrays)
+      .view();
+    expect(amountIn.toNumber()).to.equal(3);
+    const exactOutSig = await program.methods
+      .swapExactOut(new anchor.BN(1), new anchor.BN(4), false, expiresAtSlot)
+      .accounts(swapAccounts)
+      .remainingAccounts(swapBinArrays)
+      .rpc(confirmed);
+    await expectEvents(exactOutSig, ["Swapped"]);
+
+    // The swaps recorded observations, so a short window averages a positive
+    // price; a window older than the buffer is rejected. Growing the buffer
+    // keeps the recorded history.
+    const observationsData = (await provider.connection.getAccountInfo(
+      observations
+    ))!.data;
+    if (observationsData.readUInt32LE(8 + 32 + 2 + 1) < 8) {
+      const growSig = await program.methods
+        .growObservations(8)
+        .accounts({
+          observations,
+          payer: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+          ...eventCpi
+        })
+        .rpc(confirmed);
+      await expectEvents(growSig, ["ObservationsGrown"]);
+    }
+    const twap = await program.methods
+      .twap(new anchor.BN(1))
+      .accounts({ pool, observations })
+      .view();
+    expect(twap.gtn(0)).to.equal(true);
+    let staleTwapRejected = false;
+    try {
+      await program.methods
+        .twap(new anchor.BN(1_000_000_000))
+        .accounts({ pool, observations })
+        .view();
+    } catch (err) {
+      staleTwapRejected = true;
+    }
+    expect(staleTwapRejected).to.equal(true);
+
+    // Both swaps paid 1 B of fee into bin 1, where this position is the
+    // only LP; rounding of the per-share growth may keep 1 unit as dust.
+    const ownerTokenB = anchor.utils.token.associatedAddress({
+      mint: tokenBMint,
+      owner: provider.wallet.publicKey
+    });
+    const balanceB = async () =>
+      Number(
+        (await provider.connection.getTokenAccountBalance(ownerTokenB)).value
+          .amount
+      );
+    const balanceBeforeClaim = await balanceB();
+    const claimFeesSig = await program.methods
+      .claimFees()
       .accounts({
         pool,
         position,
-        lpMint,
-        ownerLpTokenAccount: anchor.utils.token.associatedAddress({
+        holderLpTokenAccount: anchor.utils.token.associatedAddress({
           mint: lpMint,
           owner: provider.wallet.publicKey
         }),
-        ownerTokenA: anchor.utils.token.associatedAddress({
+        holderTokenA: anchor.utils.token.associatedAddress({
           mint: tokenAMint,
           owner: provider.wallet.publicKey
         }),
-        ownerTokenB: anchor.utils.token.associatedAddress({
-          mint: tokenBMint,
-          owner: provider.wallet.publicKey
-        }),
+        holderTokenB: ownerTokenB,
         vaultA: poolVaultA,
         vaultB: poolVaultB,
+        binArrayLower,
+        binArrayUpper,
         tokenAMint,
         tokenBMint,
+        holder: provider.wallet.publicKey,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
+      })
+      .rpc(confirmed);
+    await expectEvents(claimFeesSig, ["FeesClaimed"]);
+    expect((await balanceB()) - balanceBeforeClaim).to.be.within(1, 2);
+
+    const withdrawAccounts = {
+      pool,
+      position,
+      ownerLpTokenAccount: anchor.utils.token.associatedAddress({
+        mint: lpMint,
+        owner: provider.wallet.publicKey
+      }),
+      stake,
+      ownerTokenA: anchor.utils.token.associatedAddress({
+        mint: tokenAMint,
+        owner: provider.wallet.publicKey
+      }),
+      ownerTokenB: anchor.utils.token.associatedAddress({
+        mint: tokenBMint,
+        owner: provider.wallet.publicKey
+      }),
+      vaultA: poolVaultA,
+      vaultB: poolVaultB,
+      binArrayLower,
+      binArrayUpper,
+      tokenAMint,
+      tokenBMint,
+      owner: provider.wallet.publicKey,
+      tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      ...eventCpi
+    };
+    const poolBeforeWithdraw = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    const removeSig = await program.methods
+      .removeLiquidity(5_000, new anchor.BN(0), new anchor.BN(0), expiresAtSlot)
+      .accounts(withdrawAccounts)
+      .rpc(confirmed);
+    await expectEvents(removeSig, ["LiquidityRemoved"]);
+    const poolAfterWithdraw = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    expect(poolAfterWithdraw.totalAAmount).to.be.at.most(
+      poolBeforeWithdraw.totalAAmount
+    );
+    expect(poolAfterWithdraw.totalBAmount).to.be.at.most(
+      poolBeforeWithdraw.totalBAmount
+    );
+    const positionAfterWithdraw = decodePosition(
+      (await provider.connection.getAccountInfo(position))!.data
+    );
+    expect(positionAfterWithdraw.amountA).to.equal(8);
+    expect(positionAfterWithdraw.amountB).to.equal(8);
+
+    // A flash loan of 2 B repaid in the same transaction costs a 1 B fee
+    // (9 bps, rounded up), credited to the LPs of the active bin array.
+    const borrowAccounts = {
+      pool,
+      vault: poolVaultB,
+      borrowerToken: withdrawAccounts.ownerTokenB,
+      binArray: binArrayUpper,
+      borrower: provider.wallet.publicKey,
+      instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      ...eventCpi
+    };
+    const borrowIx = await program.methods
+      .flashLoanBorrow(new anchor.BN(2), false)
+      .accounts(borrowAccounts)
+      .instruction();
+    const repayIx = await program.methods
+      .flashLoanRepay()
+      .accounts({
+        pool,
+        vault: poolVaultB,
+        borrowerToken: withdrawAccounts.ownerTokenB,
+        binArray: binArrayUpper,
+        borrower: provider.wallet.publicKey,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
+      })
+      .instruction();
+    const balanceBeforeLoan = await balanceB();
+    const loanSig = await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(borrowIx, repayIx),
+      [],
+      confirmed
+    );
+    await expectEvents(loanSig, ["FlashLoanBorrowed", "FlashLoanRepaid"]);
+    expect(await balanceB()).to.equal(balanceBeforeLoan - 1);
+    const poolAfterLoan = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    expect(poolAfterLoan.flashLoanAmount).to.equal(0);
+
+    // A borrow without a later repay in the same transaction is rejected.
+    let borrowRejected = false;
+    try {
+      await program.methods
+        .flashLoanBorrow(new anchor.BN(2), false)
+        .accounts(borrowAccounts)
+        .rpc();
+    } catch (err) {
+      borrowRejected = true;
+    }
+    expect(borrowRejected).to.equal(true);
+
+    // The pool cannot be swapped while a loan is out, so the borrower cannot
+    // move the active bin away from the LPs who earn the fee.
+    const swapIx = await program.methods
+      .swap(new anchor.BN(3), new anchor.BN(0), false, expiresAtSlot)
+      .accounts(swapAccounts)
+      .remainingAccounts(swapBinArrays)
+      .instruction();
+    let swapDuringLoanRejected = false;
+    try {
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(borrowIx, swapIx, repayIx),
+        [],
+        confirmed
+      );
+    } catch (err) {
+      swapDuringLoanRejected = true;
+    }
+    expect(swapDuringLoanRejected).to.equal(true);
+
+    // A limit order selling 3 A rests one bin above the active bin. Nothing
+    // has crossed it yet, so a claim pays nothing and cancelling refunds it.
+    const ownerTokenA = withdrawAccounts.ownerTokenA;
+    const balanceA = async () =>
+      Number(
+        (await provider.connection.getTokenAccountBalance(ownerTokenA)).value
+          .amount
+      );
+    const orderSeed = Buffer.alloc(8);
+    orderSeed.writeBigUInt64LE(BigInt(poolAfterLoan.nextOrderId));
+    const [limitOrder] = PublicKey.findProgramAddressSync(
+      [Buffer.from("limit_order"), pool.toBuffer(), orderSeed],
+      programId
+    );
+    const placeOrder = (binId: number, deadline = expiresAtSlot) =>
+      program.methods
+        .placeLimitOrder(binId, true, new anchor.BN(3), deadline)
+        .accounts({
+          pool,
+          order: limitOrder,
+          binArray: binArrayUpper,
+          vault: poolVaultA,
+          ownerToken: ownerTokenA,
+          owner: provider.wallet.publicKey,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          ...eventCpi
+        })
+        .rpc(confirmed);
+
+    // Orders selling A may not rest in the active bin.
+    let activeBinOrderRejected = false;
+    try {
+      await placeOrder(poolAfterLoan.activeBinId);
+    } catch (err) {
+      activeBinOrderRejected = true;
+    }
+    expect(activeBinOrderRejected).to.equal(true);
+
+    // Orders carry a deadline like swaps and liquidity changes.
+    let expiredOrderRejected = false;
+    try {
+      await placeOrder(poolAfterLoan.activeBinId + 1, new anchor.BN(0));
+    } catch (err) {
+      expiredOrderRejected = true;
+    }
+    expect(expiredOrderRejected).to.equal(true);
+
+    const balanceBeforeOrder = await balanceA();
+    const placeSig = await placeOrder(poolAfterLoan.activeBinId + 1);
+    await expectEvents(placeSig, ["LimitOrderPlaced"]);
+    expect(await balanceA()).to.equal(balanceBeforeOrder - 3);
+
+    const balanceBeforeOrderClaim = await balanceB();
+    const orderClaimSig = await program.methods
+      .claimFilledOrder(expiresAtSlot)
+      .accounts({
+        pool,
+        order: limitOrder,
+        binArray: binArrayUpper,
+        vault: poolVaultB,
+        ownerToken: ownerTokenB,
         owner: provider.wallet.publicKey,
-        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
       })
-      .rpc();
+      .rpc(confirmed);
+    await expectEvents(orderClaimSig, ["LimitOrderClaimed"]);
+    expect(await balanceB()).to.equal(balanceBeforeOrderClaim);
 
-    const poolAfterLiquidity = await provider.connection.getAccountInfo(pool);
-    expect(poolAfterLiquidity).to.not.equal(null);
-    const parsedPoolAfter = decodePool(poolAfterLiquidity!.data);
-    expect(parsedPoolAfter.totalAAmount).to.equal(15);
-    expect(parsedPoolAfter.totalBAmount).to.equal(15);
+    const cancelSig = await program.methods
+      .cancelLimitOrder(expiresAtSlot)
+      .accounts({
+        pool,
+        order: limitOrder,
+        binArray: binArrayUpper,
+        vaultIn: poolVaultA,
+        ownerTokenIn: ownerTokenA,
+        vaultOut: poolVaultB,
+        ownerTokenOut: ownerTokenB,
+        owner: provider.wallet.publicKey,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
+      })
+      .rpc(confirmed);
+    await expectEvents(cancelSig, ["LimitOrderCancelled"]);
+    expect(await balanceA()).to.equal(balanceBeforeOrder);
+    expect(await provider.connection.getAccountInfo(limitOrder)).to.equal(null);
 
-    await program.methods
+    // The position is the pool's only LP, so closing it drains every bin.
+    const closeSig = await program.methods
       .closePosition()
+      .accounts({ ...withdrawAccounts, lpMint })
+      .rpc(confirmed);
+    await expectEvents(closeSig, ["PositionClosed"]);
+    const poolAfterClose = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    expect(poolAfterClose.totalAAmount).to.equal(0);
+    expect(poolAfterClose.totalBAmount).to.equal(0);
+
+    // The config treasury defaults to the admin, so its own ATAs receive the
+    // house share of the swap fees.
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      programId
+    );
+    const houseFeesSig = await program.methods
+      .withdrawHouseFees()
       .accounts({
+        config,
         pool,
-        position,
-        lpMint,
-        ownerLpTokenAccount: anchor.utils.token.associatedAddress({
-          mint: lpMint,
-          owner: provider.wallet.publicKey
-        }),
-        stake,
-        owner: provider.wallet.publicKey,
-        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID
+        vaultA: poolVaultA,
+        vaultB: poolVaultB,
+        treasuryTokenA: withdrawAccounts.ownerTokenA,
+        treasuryTokenB: withdrawAccounts.ownerTokenB,
+        tokenAMint,
+        tokenBMint,
+        admin: provider.wallet.publicKey,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        ...eventCpi
       })
-      .rpc();
+      .rpc(confirmed);
+    await expectEvents(houseFeesSig, ["HouseFeesWithdrawn"]);
+    const poolAfterHouseWithdraw = decodePool(
+      (await provider.connection.getAccountInfo(pool))!.data
+    );
+    expect(poolAfterHouseWithdraw.houseFeesA).to.equal(0);
+    expect(poolAfterHouseWithdraw.houseFeesB).to.equal(0);
   });
 });
@@ -1,6 +1,42 @@
+import math
 import unittest
+from pathlib import Path
 
-from origin_dex_sdk import preview_allocation, preview_allocation_from_functions
+from origin_dex_sdk import (
+    FAMILY_FUNCTIONS,
+    FUNCTION_CURVE,
+    FUNCTION_PRESET_SIGMOID,
+    PARAM_SCALE,
+    preview_allocation,
+    preview_allocation_from_functions,
+    preset_function_spec,
+    program_allocation,
+    program_function_spec,
+    program_weights,
+    weights_from_function,
+)
+
+GOLDEN_VECTORS = Path(__file__).parent / "golden" / "allocation_functions.txt"
+
+
+def load_golden_vectors():
+    vectors = []
+    for line in GOLDEN_VECTORS.read_text().splitlines():
+        if line.startswith("#"):
+            continue
+        family, function_type, params, bins, amount, weights, allocation = line.split()
+        vectors.append(
+            (
+                family,
+                int(function_type),
+                tuple(int(value) for value in params.split(",")),
+                int(bins),
+                int(amount),
+                tuple(int(value) for value in weights.split(",")),
+                tuple(int(value) for value in allocation.split(",")),
+            )
+        )
+    return vectors
 
 
 class AllocationPreviewTests(unittest.TestCase):
@@ -35,5 +71,84 @@ class AllocationPreviewTests(unittest.TestCase):
         self.assertEqual(sum(result.left) + sum(result.right), 12)
 
 
+class ProgramFunctionTests(unittest.TestCase):
+    def test_golden_vectors_match_program_engine(self):
+        for family, function_type, params, bins, amount, weights, allocation in (
+            load_golden_vectors()
+        ):
+            with self.subTest(family=family, params=params, bins=bins):
+                self.assertEqual(program_weights(function_type, params, bins), weights)
+                self.assertEqual(program_allocation(amount, weights), allocation)
+
+    def test_golden_vectors_track_float_families(self):
+        for family, function_type, params, bins, _, weights, _ in load_golden_vectors():
+            if family not in FAMILY_FUNCTIONS:
+                continue
+            _, names = FAMILY_FUNCTIONS[family]
+            decoded = {name: params[i] / PARAM_SCALE for i, name in enumerate(names)}
+            with self.subTest(family=family, params=params, bins=bins):
+                function_spec = program_function_spec(family, bins, decoded)
+                self.assertEqual(function_spec[0], function_type)
+                expected = weights_from_function(family, bins, decoded)
+                for weight, reference in zip(weights, expected):
+                    self.assertTrue(
+                        math.isclose(
+                            weight / PARAM_SCALE, reference, rel_tol=1e-5, abs_tol=2e-6
+                        ),
+                        (weight, reference),
+                    )
+
+    def test_preset_vectors_track_readme_curves(self):
+        def logistic(k, m, x):
+            return 1 / (1 + math.exp(-k * (x - m)))
+
+        curves = {
+            "preset_power": lambda s, m, x: x**s,
+            "preset_exp": lambda s, m, x: math.expm1(s * x) / math.expm1(s),
+            "preset_log": lambda s, m, x: math.log1p(s * x) / math.log1p(s),
+            "preset_sigmoid": lambda s, m, x: (logistic(s, m, x) - logistic(s, m, 0))
+            / (logistic(s, m, 1) - logistic(s, m, 0)),
+        }
+        for family, _, params, bins, _, weights, _ in load_golden_vectors():
+            if family not in curves:
+                continue
+            base, amp, shape, midpoint = (value / PARAM_SCALE for value in params[:4])
+            with self.subTest(family=family, params=params, bins=bins):
+                for d, weight in enumerate(weights, start=1):
+                    x = (d - 1) / (bins - 1) if bins > 1 else 0
+                    reference = base + amp * curves[family](shape, midpoint, x)
+                    self.assertTrue(
+                        math.isclose(
+                            weight / PARAM_SCALE, reference, rel_tol=1e-5, abs_tol=2e-6
+                        ),
+                        (weight, reference),
+                    )
+
+    def test_preset_function_spec_encodes_param_api(self):
+        self.assertEqual(
+            preset_function_spec(
+                {"type": "sigmoid", "k": 12, "m": 0.65, "base": 1, "amp": 200}
+            ),
+            (
+                FUNCTION_PRESET_SIGMOID,
+                (PARAM_SCALE, 200 * PARAM_SCALE, 12 * PARAM_SCALE, 650_000, 0),
+            ),
+        )
+        with self.assertRaises(ValueError):
+            preset_function_spec({"type": "cubic"})
+
+    def test_program_function_spec_applies_defaults(self):
+        self.assertEqual(
+            program_function_spec("meteora_curve", 10, {}),
+            (FUNCTION_CURVE, (5 * PARAM_SCALE, 0, 0, 0, 0)),
+        )
+        with self.assertRaises(ValueError):
+            program_function_spec("unknown", 4, {})
+
+    def test_program_allocation_rejects_zero_weights(self):
+        with self.assertRaises(ValueError):
+            program_allocation(10, [0, 0])
+
+
 if __name__ == "__main__":
     unittest.main()